@@ -0,0 +1,191 @@
+// What the driver can do, all in one table. The query_* entry points, get_config_attributes, the
+// VADriverContext max_* fields and create_config's checks are all worked out from it, so adding a
+// codec or format means adding to CAPABILITIES (and writing the code that does it, of course).
+
+use crate::sys::*;
+
+#[derive(Debug)]
+pub struct Capability {
+    pub profile: VAProfile,
+    pub entrypoint: VAEntrypoint,
+    pub rt_formats: u32, // VA_RT_FORMAT_* mask
+    // surfaces read from, and written to by VPP
+    pub input_fourccs: &'static [u32],
+    pub output_fourccs: &'static [u32],
+    pub max_width: u32,
+    pub max_height: u32,
+    pub encode: Option<EncodeCaps>,
+}
+
+#[derive(Debug)]
+pub struct EncodeCaps {
+    pub rc_modes: u32, // VA_RC_* mask
+    // the level is only known once frames come in, so this is for the biggest one there is
+    pub max_frame_macroblocks: u32,
+    // RefPicList0 only. Long-term references are in there too, but x264 can only keep one by
+    // keeping every frame since, so they last 16 frames (MAX_DPB in lib.rs). Naming an older one
+    // gets an IDR instead
+    pub max_ref_frames: u32,
+    pub qp_block_size: u32,
+    // x264 has no way to keep a P frame out of the reference list, so no hierarchical P. 1 means
+    // a single layer, i.e. no temporal scalability
+    pub max_temporal_layers: u32,
+}
+
+const YUV_FOURCCS: &[u32] = &[
+    VA_FOURCC_NV12,
+    VA_FOURCC_I420,
+    VA_FOURCC_YV12,
+    VA_FOURCC_YUY2,
+    VA_FOURCC_UYVY,
+    VA_FOURCC_422H,
+    VA_FOURCC_444P,
+    VA_FOURCC_Y800,
+];
+
+pub const CAPABILITIES: &[Capability] = &[
+    Capability {
+        profile: VAProfile_VAProfileH264Main,
+        entrypoint: VAEntrypoint_VAEntrypointEncPicture,
+        // anything that isn't 4:2:0 is subsampled to it on the way into x264
+        rt_formats: VA_RT_FORMAT_YUV420
+            | VA_RT_FORMAT_YUV422
+            | VA_RT_FORMAT_YUV444
+            | VA_RT_FORMAT_YUV400,
+        input_fourccs: YUV_FOURCCS,
+        output_fourccs: &[],
+        max_width: 16384,
+        max_height: 16384,
+        encode: Some(EncodeCaps {
+            rc_modes: VA_RC_CBR | VA_RC_VBR | VA_RC_CQP,
+            max_frame_macroblocks: 139264, // MaxFS for level 6.2, e.g. 8192x4352
+            max_ref_frames: 10,            // TODO(RG)!
+            qp_block_size: 16,             // one QP per macroblock
+            max_temporal_layers: 1,
+        }),
+    },
+    // colour conversion only, RGB to YUV and between YUV formats
+    Capability {
+        profile: VAProfile_VAProfileNone,
+        entrypoint: VAEntrypoint_VAEntrypointVideoProc,
+        rt_formats: VA_RT_FORMAT_YUV420
+            | VA_RT_FORMAT_YUV422
+            | VA_RT_FORMAT_YUV444
+            | VA_RT_FORMAT_YUV400
+            | VA_RT_FORMAT_RGB16
+            | VA_RT_FORMAT_RGB32
+            | VA_RT_FORMAT_RGB32_10,
+        input_fourccs: &[
+            VA_FOURCC_RGBA,
+            VA_FOURCC_RGBX,
+            VA_FOURCC_BGRA,
+            VA_FOURCC_BGRX,
+            VA_FOURCC_ARGB,
+            VA_FOURCC_XRGB,
+            VA_FOURCC_RGB565,
+            VA_FOURCC_X2R10G10B10,
+            VA_FOURCC_NV12,
+            VA_FOURCC_I420,
+            VA_FOURCC_YV12,
+            VA_FOURCC_YUY2,
+            VA_FOURCC_UYVY,
+            VA_FOURCC_422H,
+            VA_FOURCC_444P,
+            VA_FOURCC_Y800,
+        ],
+        output_fourccs: YUV_FOURCCS,
+        max_width: 16384,
+        max_height: 16384,
+        encode: None,
+    },
+];
+
+pub fn find(profile: VAProfile, entrypoint: VAEntrypoint) -> Result<&'static Capability, VAStatus> {
+    let mut known_profile = false;
+    for cap in CAPABILITIES {
+        if cap.profile == profile {
+            if cap.entrypoint == entrypoint {
+                return Ok(cap);
+            }
+            known_profile = true;
+        }
+    }
+
+    Err(if known_profile {
+        VA_STATUS_ERROR_UNSUPPORTED_ENTRYPOINT
+    } else {
+        VA_STATUS_ERROR_UNSUPPORTED_PROFILE
+    })
+}
+
+// what surfaces can be created in: our own memory, or imported from the application's
+pub const MEMORY_TYPES: u32 = VA_SURFACE_ATTRIB_MEM_TYPE_VA
+    | VA_SURFACE_ATTRIB_MEM_TYPE_DRM_PRIME
+    | VA_SURFACE_ATTRIB_MEM_TYPE_DRM_PRIME_2
+    | VA_SURFACE_ATTRIB_MEM_TYPE_USER_PTR;
+
+// what a surface can be, whatever it ends up being used for
+pub fn max_size() -> (u32, u32) {
+    CAPABILITIES.iter().fold((0, 0), |(width, height), cap| {
+        (width.max(cap.max_width), height.max(cap.max_height))
+    })
+}
+
+impl Capability {
+    pub fn check_size(&self, width: u32, height: u32) -> Result<(), VAStatus> {
+        let macroblocks = |n: u32| (n + 15) / 16;
+        let too_many_macroblocks = self.encode.as_ref().is_some_and(|enc| {
+            macroblocks(width) * macroblocks(height) > enc.max_frame_macroblocks
+        });
+
+        if width == 0
+            || height == 0
+            || width > self.max_width
+            || height > self.max_height
+            || too_many_macroblocks
+        {
+            return Err(VA_STATUS_ERROR_RESOLUTION_NOT_SUPPORTED);
+        }
+        Ok(())
+    }
+}
+
+// each once, in table order
+pub fn profiles() -> Vec<VAProfile> {
+    let mut profiles = Vec::new();
+    for cap in CAPABILITIES {
+        if !profiles.contains(&cap.profile) {
+            profiles.push(cap.profile);
+        }
+    }
+    profiles
+}
+
+pub fn entrypoints(profile: VAProfile) -> Vec<VAEntrypoint> {
+    CAPABILITIES
+        .iter()
+        .filter(|cap| cap.profile == profile)
+        .map(|cap| cap.entrypoint)
+        .collect()
+}
+
+pub fn max_entrypoints() -> usize {
+    profiles()
+        .into_iter()
+        .map(|profile| entrypoints(profile).len())
+        .max()
+        .unwrap_or(0)
+}
+
+// every format some entry point reads or writes, each once
+pub fn fourccs() -> Vec<u32> {
+    let mut fourccs = Vec::new();
+    for cap in CAPABILITIES {
+        for fourcc in cap.input_fourccs.iter().chain(cap.output_fourccs) {
+            if !fourccs.contains(fourcc) {
+                fourccs.push(*fourcc);
+            }
+        }
+    }
+    fourccs
+}
@@ -0,0 +1,285 @@
+// Per-deployment encoder settings. These come from the file named by LIBVA_X264_CONFIG, then
+// LIBVA_X264_OPTS, so the environment wins over the file. Both are `key=value` pairs, one per
+// line in the file (# starts a comment) and comma separated in the environment, e.g.
+//
+//     LIBVA_X264_OPTS=preset=veryfast,tune=none,zerolatency=1,threads=4
+//
+// Keys:
+//     preset          ultrafast ... placebo
+//     tune            none, film, animation, grain, stillimage, psnr, ssim
+//     fastdecode      bool
+//     zerolatency     bool
+//     threads         number of threads, 0 for x264 to decide
+//     sliced-threads  bool. Threads splitting frames rather than slices hold frames back, which
+//                     zerolatency rules out, so 0 only takes with zerolatency=0
+//     profile         baseline, main, high
+
+use std::{env, fs};
+
+use x264::{Preset, Tune};
+
+const CONFIG_FILE_VAR: &str = "LIBVA_X264_CONFIG";
+const OPTS_VAR: &str = "LIBVA_X264_OPTS";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    Baseline,
+    Main,
+    High,
+}
+
+impl Profile {
+    // for x264_param_apply_profile
+    pub fn to_cstr(self) -> *const std::os::raw::c_char {
+        (match self {
+            Profile::Baseline => b"baseline\0" as *const u8,
+            Profile::Main => b"main\0" as *const u8,
+            Profile::High => b"high\0" as *const u8,
+        }) as *const _
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DriverConfig {
+    pub preset: Preset,
+    pub tune: Tune,
+    pub fast_decode: bool,
+    pub zero_latency: bool,
+    pub threads: Option<i32>,
+    pub sliced_threads: Option<bool>,
+    pub profile: Option<Profile>,
+
+    // every option that took, in order, so a trace can rebuild the same config
+    pub opts: Vec<String>,
+}
+
+impl Default for DriverConfig {
+    fn default() -> Self {
+        Self {
+            preset: Preset::Ultrafast,
+            tune: Tune::StillImage,
+            fast_decode: false,
+            zero_latency: true,
+            threads: None,
+            sliced_threads: None,
+            profile: None,
+            opts: Vec::new(),
+        }
+    }
+}
+
+impl DriverConfig {
+    // anything that doesn't parse is reported and skipped, a typo shouldn't stop the driver loading
+    pub fn load() -> Self {
+        let mut config = DriverConfig::default();
+
+        if let Some(path) = env::var_os(CONFIG_FILE_VAR) {
+            match fs::read_to_string(&path) {
+                Ok(contents) => {
+                    for line in contents.lines() {
+                        let line = line.split('#').next().unwrap().trim();
+                        config.apply(line, &path.to_string_lossy());
+                    }
+                }
+                Err(e) => warn!("can't read {}: {e}", path.to_string_lossy()),
+            }
+        }
+
+        if let Ok(opts) = env::var(OPTS_VAR) {
+            for opt in opts.split(',') {
+                config.apply(opt.trim(), OPTS_VAR);
+            }
+        }
+
+        config.settle();
+        config
+    }
+
+    pub fn from_opts(opts: &[String]) -> Self {
+        let mut config = DriverConfig::default();
+        for opt in opts {
+            config.apply(opt, "trace");
+        }
+        config.settle();
+        config
+    }
+
+    fn apply(&mut self, opt: &str, source: &str) {
+        if opt.is_empty() {
+            return;
+        }
+
+        let res = match opt.split_once('=') {
+            Some((key, value)) => self.set(key.trim(), value.trim()),
+            None => Err("expected key=value".to_owned()),
+        };
+        match res {
+            Ok(()) => self.opts.push(opt.to_owned()),
+            Err(e) => warn!("ignoring `{opt}` from {source}: {e}"),
+        }
+    }
+
+    // options that only take together with others, once all of them are in
+    fn settle(&mut self) {
+        if self.zero_latency && self.sliced_threads == Some(false) {
+            warn!(
+                "ignoring sliced-threads=0, frame threads would hold frames back from zerolatency"
+            );
+            self.sliced_threads = None;
+        }
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "preset" => self.preset = parse_preset(value)?,
+            "tune" => self.tune = parse_tune(value)?,
+            "fastdecode" => self.fast_decode = parse_bool(value)?,
+            "zerolatency" => self.zero_latency = parse_bool(value)?,
+            "threads" => {
+                self.threads = Some(
+                    value
+                        .parse::<u16>()
+                        .map_err(|e| format!("bad thread count: {e}"))?
+                        .into(),
+                )
+            }
+            "sliced-threads" => self.sliced_threads = Some(parse_bool(value)?),
+            "profile" => {
+                self.profile = Some(match value {
+                    "baseline" => Profile::Baseline,
+                    "main" => Profile::Main,
+                    "high" => Profile::High,
+                    _ => return Err("unknown profile".to_owned()),
+                })
+            }
+            _ => return Err("unknown key".to_owned()),
+        }
+        Ok(())
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "1" | "true" | "yes" | "on" => Ok(true),
+        "0" | "false" | "no" | "off" => Ok(false),
+        _ => Err("expected a boolean".to_owned()),
+    }
+}
+
+fn parse_preset(value: &str) -> Result<Preset, String> {
+    Ok(match value {
+        "ultrafast" => Preset::Ultrafast,
+        "superfast" => Preset::Superfast,
+        "veryfast" => Preset::Veryfast,
+        "faster" => Preset::Faster,
+        "fast" => Preset::Fast,
+        "medium" => Preset::Medium,
+        "slow" => Preset::Slow,
+        "slower" => Preset::Slower,
+        "veryslow" => Preset::Veryslow,
+        "placebo" => Preset::Placebo,
+        _ => return Err("unknown preset".to_owned()),
+    })
+}
+
+fn parse_tune(value: &str) -> Result<Tune, String> {
+    Ok(match value {
+        "none" => Tune::None,
+        "film" => Tune::Film,
+        "animation" => Tune::Animation,
+        "grain" => Tune::Grain,
+        "stillimage" => Tune::StillImage,
+        "psnr" => Tune::Psnr,
+        "ssim" => Tune::Ssim,
+        _ => return Err("unknown tune".to_owned()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opts(opts: &[&str]) -> Vec<String> {
+        opts.iter().map(|opt| opt.to_string()).collect()
+    }
+
+    #[test]
+    fn every_key() {
+        let config = DriverConfig::from_opts(&opts(&[
+            "preset=veryslow",
+            "tune=film",
+            "fastdecode=yes",
+            "zerolatency=off",
+            "threads=6",
+            "sliced-threads=0",
+            "profile=high",
+        ]));
+        assert!(matches!(config.preset, Preset::Veryslow));
+        assert!(matches!(config.tune, Tune::Film));
+        assert!(config.fast_decode);
+        assert!(!config.zero_latency);
+        assert_eq!(config.threads, Some(6));
+        assert_eq!(config.sliced_threads, Some(false));
+        assert_eq!(config.profile, Some(Profile::High));
+        assert_eq!(config.opts.len(), 7);
+    }
+
+    #[test]
+    fn defaults() {
+        let config = DriverConfig::from_opts(&[]);
+        assert!(matches!(config.preset, Preset::Ultrafast));
+        assert!(config.zero_latency);
+        assert_eq!(config.threads, None);
+        assert_eq!(config.sliced_threads, None);
+        assert_eq!(config.profile, None);
+        assert!(config.opts.is_empty());
+    }
+
+    #[test]
+    fn frame_threads_need_latency() {
+        let config = DriverConfig::from_opts(&opts(&["sliced-threads=0"]));
+        assert_eq!(config.sliced_threads, None);
+        let config = DriverConfig::from_opts(&opts(&["sliced-threads=1"]));
+        assert_eq!(config.sliced_threads, Some(true));
+        // wherever zerolatency comes
+        let config = DriverConfig::from_opts(&opts(&["sliced-threads=no", "zerolatency=0"]));
+        assert_eq!(config.sliced_threads, Some(false));
+    }
+
+    #[test]
+    fn bad_options_are_skipped() {
+        let config = DriverConfig::from_opts(&opts(&[
+            "preset=fast",
+            "preset=warp",
+            "threads=-1",
+            "threads=many",
+            "profile=extended",
+            "zerolatency=maybe",
+            "colour=red",
+            "threads",
+            "",
+        ]));
+        // the good one before them still counts
+        assert!(matches!(config.preset, Preset::Fast));
+        assert_eq!(config.threads, None);
+        assert_eq!(config.profile, None);
+        assert!(config.zero_latency);
+        assert_eq!(config.opts, opts(&["preset=fast"]));
+    }
+
+    #[test]
+    fn later_options_win() {
+        let config = DriverConfig::from_opts(&opts(&["threads=2", " threads = 3 "]));
+        assert_eq!(config.threads, Some(3));
+        assert_eq!(config.opts, opts(&["threads=2", " threads = 3 "]));
+    }
+
+    #[test]
+    fn opts_rebuild_the_config() {
+        let config = DriverConfig::from_opts(&opts(&["tune=psnr", "bogus", "profile=main"]));
+        let rebuilt = DriverConfig::from_opts(&config.opts);
+        assert!(matches!(rebuilt.tune, Tune::Psnr));
+        assert_eq!(rebuilt.profile, Some(Profile::Main));
+        assert_eq!(rebuilt.opts, config.opts);
+    }
+}
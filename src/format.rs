@@ -0,0 +1,709 @@
+// How each fourcc a surface can be in is laid out in memory, which is what allocation, import,
+// export, the VAImageFormats handed out and the conversions below all go by. YUV is always 8 bits
+// per component, RGB is whatever its masks say.
+
+use crate::{align_up, sys::*, PlaneInfo};
+
+#[derive(Debug)]
+pub struct Layout {
+    pub fourcc: u32,
+    pub rt_format: u32,  // VA_RT_FORMAT_*
+    pub drm_format: u32, // what it's called in drm_fourcc.h, for export
+    pub planes: &'static [Plane],
+    // Y, U and V in that order, empty for RGB. Without U and V it's greyscale
+    pub yuv: &'static [Component],
+    pub masks: Masks, // all 0 for YUV
+}
+
+// which bits of the little-endian pixel each RGB channel is. The fourcc names the bytes in memory
+// order, so e.g. RGBA has red in the low byte
+#[derive(Debug, Clone, Copy)]
+pub struct Masks {
+    pub red: u32,
+    pub green: u32,
+    pub blue: u32,
+    pub alpha: u32,
+}
+
+const NO_MASKS: Masks = Masks {
+    red: 0,
+    green: 0,
+    blue: 0,
+    alpha: 0,
+};
+
+// each `bytes` long sample covers h_sub x v_sub pixels
+#[derive(Debug)]
+pub struct Plane {
+    pub h_sub: usize,
+    pub v_sub: usize,
+    pub bytes: usize,
+}
+
+// where one of Y, U or V sits: its plane, how far into a sample, how far apart, and how many
+// pixels each one covers
+#[derive(Debug)]
+pub struct Component {
+    plane: usize,
+    offset: usize,
+    step: usize,
+    h_sub: usize,
+    v_sub: usize,
+}
+
+const fn code(code: &[u8; 4]) -> u32 {
+    u32::from_le_bytes(*code)
+}
+
+const FULL: Plane = Plane {
+    h_sub: 1,
+    v_sub: 1,
+    bytes: 1,
+};
+const HALF: Plane = Plane {
+    h_sub: 2,
+    v_sub: 1,
+    bytes: 1,
+};
+const QUARTER: Plane = Plane {
+    h_sub: 2,
+    v_sub: 2,
+    bytes: 1,
+};
+
+const RGB16: [Plane; 1] = [Plane {
+    h_sub: 1,
+    v_sub: 1,
+    bytes: 2,
+}];
+const RGB32: [Plane; 1] = [Plane {
+    h_sub: 1,
+    v_sub: 1,
+    bytes: 4,
+}];
+
+// red, green, blue and alpha
+const fn rgb(
+    fourcc: u32,
+    rt_format: u32,
+    drm_format: &[u8; 4],
+    planes: &'static [Plane],
+    [red, green, blue, alpha]: [u32; 4],
+) -> Layout {
+    Layout {
+        fourcc,
+        rt_format,
+        drm_format: code(drm_format),
+        planes,
+        yuv: &[],
+        masks: Masks {
+            red,
+            green,
+            blue,
+            alpha,
+        },
+    }
+}
+
+const Y: Component = Component {
+    plane: 0,
+    offset: 0,
+    step: 1,
+    h_sub: 1,
+    v_sub: 1,
+};
+
+const fn planar(plane: usize, h_sub: usize, v_sub: usize) -> Component {
+    Component {
+        plane,
+        offset: 0,
+        step: 1,
+        h_sub,
+        v_sub,
+    }
+}
+
+// YUY2 and UYVY: one plane, four bytes for every two pixels
+const fn packed(offset: usize, step: usize, h_sub: usize) -> Component {
+    Component {
+        plane: 0,
+        offset,
+        step,
+        h_sub,
+        v_sub: 1,
+    }
+}
+
+pub const LAYOUTS: &[Layout] = &[
+    Layout {
+        fourcc: VA_FOURCC_NV12,
+        rt_format: VA_RT_FORMAT_YUV420,
+        drm_format: code(b"NV12"),
+        planes: &[
+            FULL,
+            Plane {
+                h_sub: 2,
+                v_sub: 2,
+                bytes: 2,
+            },
+        ],
+        yuv: &[
+            Y,
+            Component {
+                plane: 1,
+                offset: 0,
+                step: 2,
+                h_sub: 2,
+                v_sub: 2,
+            },
+            Component {
+                plane: 1,
+                offset: 1,
+                step: 2,
+                h_sub: 2,
+                v_sub: 2,
+            },
+        ],
+        masks: NO_MASKS,
+    },
+    Layout {
+        fourcc: VA_FOURCC_I420,
+        rt_format: VA_RT_FORMAT_YUV420,
+        drm_format: code(b"YU12"),
+        planes: &[FULL, QUARTER, QUARTER],
+        yuv: &[Y, planar(1, 2, 2), planar(2, 2, 2)],
+        masks: NO_MASKS,
+    },
+    Layout {
+        fourcc: VA_FOURCC_YV12,
+        rt_format: VA_RT_FORMAT_YUV420,
+        drm_format: code(b"YV12"),
+        planes: &[FULL, QUARTER, QUARTER],
+        yuv: &[Y, planar(2, 2, 2), planar(1, 2, 2)],
+        masks: NO_MASKS,
+    },
+    Layout {
+        fourcc: VA_FOURCC_YUY2,
+        rt_format: VA_RT_FORMAT_YUV422,
+        drm_format: code(b"YUYV"),
+        planes: &[Plane {
+            h_sub: 2,
+            v_sub: 1,
+            bytes: 4,
+        }],
+        yuv: &[packed(0, 2, 1), packed(1, 4, 2), packed(3, 4, 2)],
+        masks: NO_MASKS,
+    },
+    Layout {
+        fourcc: VA_FOURCC_UYVY,
+        rt_format: VA_RT_FORMAT_YUV422,
+        drm_format: code(b"UYVY"),
+        planes: &[Plane {
+            h_sub: 2,
+            v_sub: 1,
+            bytes: 4,
+        }],
+        yuv: &[packed(1, 2, 1), packed(0, 4, 2), packed(2, 4, 2)],
+        masks: NO_MASKS,
+    },
+    Layout {
+        fourcc: VA_FOURCC_422H,
+        rt_format: VA_RT_FORMAT_YUV422,
+        drm_format: code(b"YU16"),
+        planes: &[FULL, HALF, HALF],
+        yuv: &[Y, planar(1, 2, 1), planar(2, 2, 1)],
+        masks: NO_MASKS,
+    },
+    Layout {
+        fourcc: VA_FOURCC_444P,
+        rt_format: VA_RT_FORMAT_YUV444,
+        drm_format: code(b"YU24"),
+        planes: &[FULL, FULL, FULL],
+        yuv: &[Y, planar(1, 1, 1), planar(2, 1, 1)],
+        masks: NO_MASKS,
+    },
+    Layout {
+        fourcc: VA_FOURCC_Y800,
+        rt_format: VA_RT_FORMAT_YUV400,
+        drm_format: code(b"R8  "),
+        planes: &[FULL],
+        yuv: &[Y],
+        masks: NO_MASKS,
+    },
+    // DRM names packed formats from the top bit of the little-endian pixel down, VA by bytes in
+    // memory order
+    rgb(
+        VA_FOURCC_RGBA,
+        VA_RT_FORMAT_RGB32,
+        b"AB24",
+        &RGB32,
+        [0x000000ff, 0x0000ff00, 0x00ff0000, 0xff000000],
+    ),
+    rgb(
+        VA_FOURCC_RGBX,
+        VA_RT_FORMAT_RGB32,
+        b"XB24",
+        &RGB32,
+        [0x000000ff, 0x0000ff00, 0x00ff0000, 0],
+    ),
+    rgb(
+        VA_FOURCC_BGRA,
+        VA_RT_FORMAT_RGB32,
+        b"AR24",
+        &RGB32,
+        [0x00ff0000, 0x0000ff00, 0x000000ff, 0xff000000],
+    ),
+    rgb(
+        VA_FOURCC_BGRX,
+        VA_RT_FORMAT_RGB32,
+        b"XR24",
+        &RGB32,
+        [0x00ff0000, 0x0000ff00, 0x000000ff, 0],
+    ),
+    rgb(
+        VA_FOURCC_ARGB,
+        VA_RT_FORMAT_RGB32,
+        b"BA24",
+        &RGB32,
+        [0x0000ff00, 0x00ff0000, 0xff000000, 0x000000ff],
+    ),
+    rgb(
+        VA_FOURCC_XRGB,
+        VA_RT_FORMAT_RGB32,
+        b"BX24",
+        &RGB32,
+        [0x0000ff00, 0x00ff0000, 0xff000000, 0],
+    ),
+    rgb(
+        VA_FOURCC_RGB565,
+        VA_RT_FORMAT_RGB16,
+        b"RG16",
+        &RGB16,
+        [0xf800, 0x07e0, 0x001f, 0],
+    ),
+    rgb(
+        VA_FOURCC_X2R10G10B10,
+        VA_RT_FORMAT_RGB32_10,
+        b"XR30",
+        &RGB32,
+        [0x3ff00000, 0x000ffc00, 0x000003ff, 0],
+    ),
+];
+
+pub fn layout(fourcc: u32) -> Option<&'static Layout> {
+    LAYOUTS.iter().find(|layout| layout.fourcc == fourcc)
+}
+
+impl Layout {
+    // what vaQueryImageFormats and vaDeriveImage say about it. Subsampled planes count for what
+    // they average out to per pixel, so NV12 is 12 bits
+    pub fn image_format(&self) -> VAImageFormat {
+        let bits_per_pixel = self
+            .planes
+            .iter()
+            .map(|plane| plane.bytes * 8 / (plane.h_sub * plane.v_sub))
+            .sum::<usize>();
+        let Masks {
+            red,
+            green,
+            blue,
+            alpha,
+        } = self.masks;
+
+        VAImageFormat {
+            fourcc: self.fourcc,
+            byte_order: VA_LSB_FIRST,
+            bits_per_pixel: bits_per_pixel as u32,
+            // the bits that mean something, only given for RGB
+            depth: (red | green | blue | alpha).count_ones(),
+            red_mask: red,
+            green_mask: green,
+            blue_mask: blue,
+            alpha_mask: alpha,
+            va_reserved: [0; 4],
+        }
+    }
+
+    // the least each plane needs, as (bytes per row, rows)
+    pub fn min_sizes(&self, width: u32, height: u32) -> Vec<(usize, usize)> {
+        let (width, height) = (width as usize, height as usize);
+        self.planes
+            .iter()
+            .map(|plane| {
+                (
+                    width.div_ceil(plane.h_sub) * plane.bytes,
+                    height.div_ceil(plane.v_sub),
+                )
+            })
+            .collect()
+    }
+
+    // one plane after the other. Later planes get the first one's pitch scaled down to their
+    // width, so 4:2:0 chroma rows are half as long, like everyone else lays them out
+    pub fn allocate(&self, width: u32, height: u32, align: usize) -> (Vec<PlaneInfo>, usize) {
+        let sizes = self.min_sizes(width, height);
+        let first = &self.planes[0];
+        let pitch = align_up(sizes[0].0, align);
+
+        let mut offset = 0;
+        let planes = self
+            .planes
+            .iter()
+            .zip(sizes)
+            .map(|(plane, (row, rows))| {
+                let pitch =
+                    (pitch * plane.bytes * first.h_sub / (plane.h_sub * first.bytes)).max(row);
+                let info = PlaneInfo { pitch, offset };
+                offset += pitch * rows;
+                info
+            })
+            .collect();
+        (planes, offset)
+    }
+}
+
+// YUV to YUV. Chroma is point sampled, i.e. dropped or repeated without filtering, and greyscale
+// gets neutral chroma. The colour standard stays what it was
+#[allow(clippy::too_many_arguments)]
+pub fn convert(
+    width: u32,
+    height: u32,
+    src: &Layout,
+    src_planes: &[PlaneInfo],
+    src_data: &[u8],
+    dst: &Layout,
+    dst_planes: &[PlaneInfo],
+    dst_data: &mut [u8],
+) {
+    let (width, height) = (width as usize, height as usize);
+    for (i, to) in dst.yuv.iter().enumerate() {
+        let from = src.yuv.get(i);
+        let dst_plane = &dst_planes[to.plane];
+
+        for y in 0..height.div_ceil(to.v_sub) {
+            let dst_row = dst_plane.offset + y * dst_plane.pitch + to.offset;
+            for x in 0..width.div_ceil(to.h_sub) {
+                dst_data[dst_row + x * to.step] = match from {
+                    Some(from) => {
+                        let src_plane = &src_planes[from.plane];
+                        let src_row = (y * to.v_sub / from.v_sub) * src_plane.pitch;
+                        let src_col = (x * to.h_sub / from.h_sub) * from.step;
+                        src_data[src_plane.offset + src_row + src_col + from.offset]
+                    }
+                    None => 128,
+                };
+            }
+        }
+    }
+}
+
+// any RGB format to 8 bit BGRA, which dcp takes. Alpha is dropped, VPP doesn't blend
+pub fn unpack_rgb(
+    width: u32,
+    height: u32,
+    layout: &Layout,
+    plane: &PlaneInfo,
+    data: &[u8],
+) -> Vec<u8> {
+    let (width, height) = (width as usize, height as usize);
+    let bytes = layout.planes[0].bytes;
+    let channel = |pixel: u32, mask: u32| {
+        let bits = mask.count_ones();
+        let value = (pixel & mask) >> mask.trailing_zeros();
+        // narrower channels repeat their top bits, so full scale stays full scale
+        if bits >= 8 {
+            (value >> (bits - 8)) as u8
+        } else {
+            (value << (8 - bits) | value >> (2 * bits - 8)) as u8
+        }
+    };
+
+    let mut out = Vec::with_capacity(width * height * 4);
+    for y in 0..height {
+        let row = &data[plane.offset + y * plane.pitch..];
+        for x in 0..width {
+            let mut pixel = [0; 4];
+            pixel[..bytes].copy_from_slice(&row[x * bytes..][..bytes]);
+            let pixel = u32::from_le_bytes(pixel);
+            out.extend([
+                channel(pixel, layout.masks.blue),
+                channel(pixel, layout.masks.green),
+                channel(pixel, layout.masks.red),
+                0xff,
+            ]);
+        }
+    }
+    out
+}
+
+// separate slices for each plane, for the converters that want them that way. Planes have to come
+// in memory order
+pub fn split_planes_mut<'a>(mut data: &'a mut [u8], planes: &[PlaneInfo]) -> Vec<&'a mut [u8]> {
+    let mut split = Vec::with_capacity(planes.len());
+    let mut start = 0;
+    for next in planes.iter().skip(1) {
+        let (plane, rest) = data.split_at_mut(next.offset - start);
+        split.push(plane);
+        data = rest;
+        start = next.offset;
+    }
+    split.push(data);
+    split
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // odd sizes, so rounding up subsampled planes gets exercised too
+    const WIDTH: u32 = 35;
+    const HEIGHT: u32 = 17;
+
+    fn yuv_layouts() -> impl Iterator<Item = &'static Layout> {
+        LAYOUTS.iter().filter(|layout| !layout.yuv.is_empty())
+    }
+
+    fn rgb_layouts() -> impl Iterator<Item = &'static Layout> {
+        LAYOUTS.iter().filter(|layout| layout.yuv.is_empty())
+    }
+
+    fn allocate(layout: &Layout) -> (Vec<PlaneInfo>, Vec<u8>) {
+        let (planes, size) = layout.allocate(WIDTH, HEIGHT, 64);
+        (planes, vec![0; size])
+    }
+
+    #[test]
+    fn allocate_every_layout() {
+        for layout in LAYOUTS {
+            let (planes, size) = layout.allocate(WIDTH, HEIGHT, 64);
+            let needed = layout.min_sizes(WIDTH, HEIGHT);
+            assert_eq!(planes.len(), layout.planes.len());
+            assert_eq!(planes[0].offset, 0);
+            assert_eq!(planes[0].pitch % 64, 0);
+
+            // each plane fits, and ends before the next one starts
+            let mut end = 0;
+            for (plane, (row, rows)) in planes.iter().zip(needed) {
+                assert!(plane.pitch >= row, "{layout:?}");
+                assert!(plane.offset >= end, "{layout:?}");
+                end = plane.offset + plane.pitch * rows;
+            }
+            assert_eq!(end, size, "{layout:?}");
+        }
+    }
+
+    #[test]
+    fn allocate_scales_chroma_pitch() {
+        let (planes, size) = layout(VA_FOURCC_I420).unwrap().allocate(100, 50, 128);
+        assert_eq!(planes[0].pitch, 128);
+        assert_eq!(planes[1].pitch, 64);
+        assert_eq!(planes[1].offset, 128 * 50);
+        assert_eq!(planes[2].pitch, 64);
+        assert_eq!(planes[2].offset, 128 * 50 + 64 * 25);
+        assert_eq!(size, 128 * 50 + 2 * 64 * 25);
+
+        // interleaved chroma is as wide as luma
+        let (planes, size) = layout(VA_FOURCC_NV12).unwrap().allocate(100, 50, 128);
+        assert_eq!(planes[1].pitch, 128);
+        assert_eq!(size, 128 * 75);
+    }
+
+    #[test]
+    fn image_formats() {
+        let check = |fourcc, bits_per_pixel, depth| {
+            let format = layout(fourcc).unwrap().image_format();
+            assert_eq!(format.fourcc, fourcc);
+            assert_eq!(
+                (format.bits_per_pixel, format.depth),
+                (bits_per_pixel, depth)
+            );
+        };
+        check(VA_FOURCC_NV12, 12, 0);
+        check(VA_FOURCC_I420, 12, 0);
+        check(VA_FOURCC_YUY2, 16, 0);
+        check(VA_FOURCC_422H, 16, 0);
+        check(VA_FOURCC_444P, 24, 0);
+        check(VA_FOURCC_Y800, 8, 0);
+        check(VA_FOURCC_RGBA, 32, 32);
+        check(VA_FOURCC_BGRX, 32, 24);
+        check(VA_FOURCC_RGB565, 16, 16);
+        check(VA_FOURCC_X2R10G10B10, 32, 30);
+
+        // the RGB channels never overlap
+        for layout in rgb_layouts() {
+            let Masks {
+                red,
+                green,
+                blue,
+                alpha,
+            } = layout.masks;
+            assert_eq!(
+                red & green | (red | green) & blue | (red | green | blue) & alpha,
+                0
+            );
+        }
+    }
+
+    // I420 with every sample telling where it came from
+    fn i420() -> (&'static Layout, Vec<PlaneInfo>, Vec<u8>) {
+        let i420 = layout(VA_FOURCC_I420).unwrap();
+        let (planes, mut data) = allocate(i420);
+        for (i, c) in i420.yuv.iter().enumerate() {
+            let plane = &planes[c.plane];
+            for y in 0..(HEIGHT as usize).div_ceil(c.v_sub) {
+                for x in 0..(WIDTH as usize).div_ceil(c.h_sub) {
+                    data[plane.offset + y * plane.pitch + x] = (i * 80 + x * 2 + y) as u8;
+                }
+            }
+        }
+        (i420, planes, data)
+    }
+
+    #[test]
+    fn convert_round_trip() {
+        let (i420, planes, data) = i420();
+        for layout in yuv_layouts() {
+            let (mid_planes, mut mid) = allocate(layout);
+            convert(
+                WIDTH,
+                HEIGHT,
+                i420,
+                &planes,
+                &data,
+                layout,
+                &mid_planes,
+                &mut mid,
+            );
+            let (_, mut back) = allocate(i420);
+            convert(
+                WIDTH,
+                HEIGHT,
+                layout,
+                &mid_planes,
+                &mid,
+                i420,
+                &planes,
+                &mut back,
+            );
+
+            if layout.fourcc == VA_FOURCC_Y800 {
+                // luma survives, chroma comes back neutral
+                let chroma = planes[1].offset;
+                assert_eq!(back[..chroma], data[..chroma]);
+                for plane in &planes[1..] {
+                    for y in 0..(HEIGHT as usize).div_ceil(2) {
+                        let row = &back[plane.offset + y * plane.pitch..];
+                        assert!(row[..(WIDTH as usize).div_ceil(2)]
+                            .iter()
+                            .all(|&v| v == 128));
+                    }
+                }
+            } else {
+                // everything has at least 4:2:0 chroma
+                assert_eq!(back, data, "{layout:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn convert_packs() {
+        let (i420, planes, data) = i420();
+        let sample = |plane: usize, x: usize, y: usize| {
+            data[planes[plane].offset + y * planes[plane].pitch + x]
+        };
+
+        // NV12 interleaves U and V
+        let nv12 = layout(VA_FOURCC_NV12).unwrap();
+        let (nv12_planes, mut nv12_data) = allocate(nv12);
+        convert(
+            WIDTH,
+            HEIGHT,
+            i420,
+            &planes,
+            &data,
+            nv12,
+            &nv12_planes,
+            &mut nv12_data,
+        );
+        let uv = &nv12_data[nv12_planes[1].offset + nv12_planes[1].pitch..];
+        assert_eq!(
+            uv[..4],
+            [
+                sample(1, 0, 1),
+                sample(2, 0, 1),
+                sample(1, 1, 1),
+                sample(2, 1, 1)
+            ]
+        );
+
+        // YUY2 is Y0 U Y1 V, with chroma repeated down both rows of a 4:2:0 pair
+        let yuy2 = layout(VA_FOURCC_YUY2).unwrap();
+        let (yuy2_planes, mut yuy2_data) = allocate(yuy2);
+        convert(
+            WIDTH,
+            HEIGHT,
+            i420,
+            &planes,
+            &data,
+            yuy2,
+            &yuy2_planes,
+            &mut yuy2_data,
+        );
+        let row = &yuy2_data[3 * yuy2_planes[0].pitch..];
+        assert_eq!(
+            row[..4],
+            [
+                sample(0, 0, 3),
+                sample(1, 0, 1),
+                sample(0, 1, 3),
+                sample(2, 0, 1)
+            ]
+        );
+    }
+
+    // a pixel of `layout` with each channel either full or off
+    fn pixel(layout: &Layout, [red, green, blue]: [u8; 3]) -> Vec<u8> {
+        let masks = &layout.masks;
+        let pixel = [(red, masks.red), (green, masks.green), (blue, masks.blue)]
+            .iter()
+            .filter(|(value, _)| *value == 0xff)
+            .fold(0, |pixel, (_, mask)| pixel | mask);
+        pixel.to_le_bytes()[..layout.planes[0].bytes].to_vec()
+    }
+
+    #[test]
+    fn unpack_every_rgb() {
+        for layout in rgb_layouts() {
+            let bytes = layout.planes[0].bytes;
+            let (planes, mut data) = allocate(layout);
+            let colours = [[0xff, 0, 0], [0, 0xff, 0], [0, 0, 0xff], [0xff, 0xff, 0xff]];
+            for y in 0..HEIGHT as usize {
+                for x in 0..WIDTH as usize {
+                    let at = planes[0].offset + y * planes[0].pitch + x * bytes;
+                    data[at..at + bytes].copy_from_slice(&pixel(layout, colours[(x + y) % 4]));
+                }
+            }
+
+            let bgra = unpack_rgb(WIDTH, HEIGHT, layout, &planes[0], &data);
+            assert_eq!(bgra.len(), (WIDTH * HEIGHT * 4) as usize);
+            for (i, out) in bgra.chunks(4).enumerate() {
+                let (x, y) = (i % WIDTH as usize, i / WIDTH as usize);
+                let [red, green, blue] = colours[(x + y) % 4];
+                assert_eq!(out, [blue, green, red, 0xff], "{layout:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn unpack_widens_narrow_channels() {
+        let rgb565 = layout(VA_FOURCC_RGB565).unwrap();
+        let plane = PlaneInfo {
+            pitch: 2,
+            offset: 0,
+        };
+        // red 0b10000, green 0b100000, blue 0b00001
+        let data = (0b10000 << 11 | 0b100000 << 5 | 0b00001u16).to_le_bytes();
+        let bgra = unpack_rgb(1, 1, rgb565, &plane, &data);
+        assert_eq!(bgra, [0b00001000, 0b10000010, 0b10000100, 0xff]);
+    }
+}
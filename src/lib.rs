@@ -1,76 +1,123 @@
-#![feature(array_try_from_fn)]
+#[macro_use]
+mod log;
 
+mod caps;
+mod config;
+mod format;
 mod sys;
+mod table;
+mod trace;
 
 use dcp::{convert_image, ImageFormat, PixelFormat};
 use dcv_color_primitives as dcp;
 
 use std::{
-    array, fmt,
+    collections::{HashMap, VecDeque},
+    ffi::CStr,
+    fmt,
     fs::File,
-    mem::{self, size_of},
+    mem::{self, size_of, MaybeUninit},
     num::NonZeroUsize,
     os::{
-        fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd},
-        raw::{c_int, c_uint, c_void},
+        fd::{AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, OwnedFd, RawFd},
+        raw::{c_char, c_int, c_uint, c_void},
     },
+    panic::{self, AssertUnwindSafe},
     ptr::{null_mut, NonNull},
     slice,
+    sync::{Mutex, MutexGuard},
 };
 
 use c_string::c_str;
+use config::DriverConfig;
+use format::Layout;
 use memfd::{FileSeal, Memfd, MemfdOptions};
 use nix::{
     ioctl_write_ptr,
-    libc::ftruncate,
-    sys::mman::{mmap, MapFlags, ProtFlags},
+    libc::{free, ftruncate, malloc},
+    sys::mman::{mmap, munmap, MapFlags, ProtFlags},
 };
 use sys::*;
-use x264::{Colorspace, Encoder, Preset, Setup, Tune};
+use table::{lock, Handle, Table};
+use trace::{Record, Recorder};
+use x264::{Colorspace, Encoding};
+use x264_sys::{
+    x264_encoder_close, x264_encoder_delayed_frames, x264_encoder_encode,
+    x264_encoder_invalidate_reference, x264_encoder_open, x264_encoder_parameters,
+    x264_encoder_reconfig, x264_image_t, x264_param_apply_profile, x264_param_default_preset,
+    x264_param_t, x264_picture_init, x264_picture_t, x264_t, X264_AQ_VARIANCE, X264_RC_ABR,
+    X264_RC_CRF, X264_TYPE_AUTO, X264_TYPE_I, X264_TYPE_IDR, X264_TYPE_P,
+};
 
 // const EGL_YUV_BUFFER_EXT: Int = 0x3300;
 
-const NUM_PROFILES: usize = 1;
-const NUM_ENTRYPOINTS: usize = 1;
-const NUM_ATTRIBUTES: usize = 1;
-const NUM_IMAGE_FORMATS: usize = 1;
+const NUM_ATTRIBUTES: usize = CONFIG_ATTRIBUTES.len();
 const NUM_SUBPIC_FORMATS: usize = 1;
 const NUM_DISPLAY_ATTRIBUTES: usize = 1;
 
+// every config attribute get_config_attributes has a value for
+const CONFIG_ATTRIBUTES: [VAConfigAttribType; 7] = [
+    VAConfigAttribType_VAConfigAttribRTFormat,
+    VAConfigAttribType_VAConfigAttribRateControl,
+    VAConfigAttribType_VAConfigAttribEncMaxRefFrames,
+    VAConfigAttribType_VAConfigAttribQPBlockSize,
+    VAConfigAttribType_VAConfigAttribEncMaxTemporalLayers,
+    VAConfigAttribType_VAConfigAttribMaxPictureWidth,
+    VAConfigAttribType_VAConfigAttribMaxPictureHeight,
+];
+
 #[derive(Debug)]
 struct Config {
     profile: VAProfile,
     entrypoint: VAEntrypoint,
     attribs: Vec<VAConfigAttrib>,
+    // what was settled on, out of everything get_config_attributes allows
+    rt_format: u32,
+    rc_mode: u32, // 0 for anything but encoding
 }
 
 enum Buffer {
     Surface {
-        buf: UDmabufAllocation,
+        buf: SurfaceMemory,
         size: usize,
         map: NonNull<u8>,
     },
     VppPipelineParameterBufferType(VAProcPipelineParameterBuffer),
-    CodedBufferSegment(Vec<u8>, VACodedBufferSegment),
+    CodedBufferSegment(Vec<u8>, VACodedBufferSegment, CodedBufferStats),
     EncSequenceParameter(VAEncSequenceParameterBufferH264),
-    EncMiscParameter(VAEncMiscParameterBuffer),
+    EncMiscParameter {
+        type_: VAEncMiscParameterType,
+        data: Vec<u8>,
+    },
     EncSliceParameter(VAEncSliceParameterBufferH264),
     EncPictureParameter(VAEncPictureParameterBufferH264),
+    EncQp(Vec<u8>), // VAEncQPBufferH264 per macroblock
     Generic {
         mem_type: u32,
         data: Vec<u8>,
     },
 }
 
+// what vaCreateBuffer's size and element count come to, unless that doesn't fit
+fn buffer_len(size: u32, num_elements: u32) -> Result<usize, VAStatus> {
+    size.checked_mul(num_elements)
+        .map(|len| len as usize)
+        .ok_or(VA_STATUS_ERROR_INVALID_PARAMETER)
+}
+
 impl Buffer {
     fn from_type_t<T>(size: u32, num_elements: u32, data: Option<&[u8]>) -> Result<T, VAStatus> {
+        if num_elements != 1 {
+            return Err(VA_STATUS_ERROR_INVALID_PARAMETER);
+        }
         if (size as usize) < num_elements as usize * size_of::<T>() {
             return Err(VA_STATUS_ERROR_INVALID_PARAMETER);
         }
 
-        Ok(data
-            .map(|data| unsafe { (data.as_ptr() as *mut T).read() })
-            .ok_or(VA_STATUS_ERROR_INVALID_PARAMETER)?)
+        // the application's memory, so no telling how it's aligned
+        data.filter(|data| data.len() >= size_of::<T>())
+            .map(|data| unsafe { (data.as_ptr() as *const T).read_unaligned() })
+            .ok_or(VA_STATUS_ERROR_INVALID_PARAMETER)
     }
 
     fn from_type(
@@ -79,7 +126,14 @@ impl Buffer {
         num_elements: u32,
         data: Option<&[u8]>,
     ) -> Result<Buffer, VAStatus> {
-        assert_eq!(num_elements, 1); // todo!
+        if type_ == VABufferType_VAEncQPBufferType {
+            // usually one element per macroblock, and filled in through vaMapBuffer
+            return Ok(Buffer::EncQp(match data {
+                Some(data) => data.to_owned(),
+                None => vec![0; buffer_len(size, num_elements)?],
+            }));
+        }
+
         Ok(match type_ {
             VABufferType_VAProcPipelineParameterBufferType => {
                 Buffer::VppPipelineParameterBufferType(Buffer::from_type_t::<
@@ -88,8 +142,14 @@ impl Buffer {
             }
             VABufferType_VAEncCodedBufferType => {
                 // NOTE: this is a linked list--what's the lifetime on it???
-                assert_eq!(data, None);
-                Buffer::CodedBufferSegment(Vec::with_capacity(size as usize), Default::default())
+                if data.is_some() {
+                    return Err(VA_STATUS_ERROR_INVALID_PARAMETER);
+                }
+                Buffer::CodedBufferSegment(
+                    Vec::with_capacity(size as usize),
+                    Default::default(),
+                    Default::default(),
+                )
                 // Buffer::CodedBufferSegment(Buffer::from_type_t::<VACodedBufferSegment>(
                 //     size,
                 //     num_elements,
@@ -99,12 +159,21 @@ impl Buffer {
             VABufferType_VAEncSequenceParameterBufferType => Buffer::EncSequenceParameter(
                 Buffer::from_type_t::<VAEncSequenceParameterBufferH264>(size, num_elements, data)?,
             ),
-            VABufferType_VAEncMiscParameterBufferType => Buffer::EncMiscParameter(
-                Buffer::from_type_t::<VAEncMiscParameterBuffer>(size, num_elements, data)?,
-            ),
-            VABufferType_VAEncSliceParameterBufferType => Buffer::EncSliceParameter(
-                Buffer::from_type_t::<VAEncSliceParameterBufferH264>(size, num_elements, data)?,
-            ),
+            VABufferType_VAEncMiscParameterBufferType => {
+                // the payload trails the header as a flexible array, so keep the raw bytes around
+                let header =
+                    Buffer::from_type_t::<VAEncMiscParameterBuffer>(size, num_elements, data)?;
+                Buffer::EncMiscParameter {
+                    type_: header.type_,
+                    data: data.unwrap()[size_of::<VAEncMiscParameterBuffer>()..].to_owned(),
+                }
+            }
+            // several slices may come in one buffer, only the first is looked at anyway
+            VABufferType_VAEncSliceParameterBufferType if num_elements > 0 => {
+                Buffer::EncSliceParameter(Buffer::from_type_t::<VAEncSliceParameterBufferH264>(
+                    size, 1, data,
+                )?)
+            }
             VABufferType_VAEncPictureParameterBufferType => Buffer::EncPictureParameter(
                 Buffer::from_type_t::<VAEncPictureParameterBufferH264>(size, num_elements, data)?,
             ),
@@ -112,56 +181,137 @@ impl Buffer {
                 mem_type: type_,
                 data: match data {
                     Some(data) => data.to_owned(),
-                    None => {
-                        let mut v = Vec::new();
-                        v.resize((size * num_elements) as usize, 0);
-                        v
-                    }
+                    None => vec![0; buffer_len(size, num_elements)?],
                 },
             },
         })
     }
 
-    fn from_surface(buf: UDmabufAllocation, size: usize) -> Buffer {
+    fn write_coded(
+        &mut self,
+        bitstream: &[u8],
+        status: u32,
+        frame_stats: CodedBufferStats,
+    ) -> Result<(), VAStatus> {
+        if let Buffer::CodedBufferSegment(raw_bytes, cbs, stats) = self {
+            raw_bytes.clear();
+            raw_bytes.extend_from_slice(bitstream);
+            *stats = frame_stats;
+
+            *cbs = VACodedBufferSegment {
+                size: raw_bytes.len() as u32,
+                bit_offset: 0,
+                status,
+                reserved: 0,
+                buf: raw_bytes.as_mut_ptr() as _,
+                next: null_mut(),
+                va_reserved: Default::default(),
+            };
+            Ok(())
+        } else {
+            Err(VA_STATUS_ERROR_INVALID_BUFFER)
+        }
+    }
+
+    fn read_misc<T>(data: &[u8]) -> Result<T, VAStatus> {
+        if data.len() < size_of::<T>() {
+            return Err(VA_STATUS_ERROR_INVALID_PARAMETER);
+        }
+
+        Ok(unsafe { (data.as_ptr() as *const T).read_unaligned() })
+    }
+
+    fn from_surface(buf: SurfaceMemory, size: usize) -> Result<Buffer, VAStatus> {
+        let fd = match &buf {
+            SurfaceMemory::Udmabuf(alloc) => alloc.memfd.as_raw_fd(),
+            SurfaceMemory::Dmabuf(fd) => fd.as_raw_fd(),
+            // already mapped, see from_user_ptr
+            SurfaceMemory::UserPtr => return Err(VA_STATUS_ERROR_INVALID_PARAMETER),
+        };
         let map = NonNull::new(
             unsafe {
                 mmap(
                     None,
-                    NonZeroUsize::new(size).unwrap(),
+                    NonZeroUsize::new(size).ok_or(VA_STATUS_ERROR_INVALID_PARAMETER)?,
                     ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
                     MapFlags::MAP_SHARED,
-                    buf.memfd.as_raw_fd(),
+                    fd,
                     0,
                 )
             }
-            .unwrap() as *mut u8,
+            .map_err(|_| VA_STATUS_ERROR_ALLOCATION_FAILED)? as *mut u8,
         )
-        .unwrap();
+        .ok_or(VA_STATUS_ERROR_ALLOCATION_FAILED)?;
 
-        Buffer::Surface { buf, size, map }
+        Ok(Buffer::Surface { buf, size, map })
     }
 
-    fn map(&self) -> &[u8] {
+    fn from_user_ptr(ptr: *mut u8, size: usize) -> Result<Buffer, VAStatus> {
+        Ok(Buffer::Surface {
+            buf: SurfaceMemory::UserPtr,
+            size,
+            map: NonNull::new(ptr).ok_or(VA_STATUS_ERROR_INVALID_PARAMETER)?,
+        })
+    }
+
+    // parameter buffers are parsed when they're created, so there's nothing left to map
+    fn map(&self) -> Result<&[u8], VAStatus> {
         let (ptr, size) = match self {
-            Buffer::CodedBufferSegment(_, cs) => {
+            Buffer::CodedBufferSegment(_, cs, _) => {
                 (cs as *const _ as _, mem::size_of::<VACodedBufferSegment>())
             }
             Buffer::Surface { size, map, .. } => (map.as_ptr() as *const _, *size),
-            _ => todo!(),
+            Buffer::EncQp(qp) => (qp.as_ptr(), qp.len()),
+            Buffer::Generic { data, .. } => (data.as_ptr(), data.len()),
+            _ => return Err(VA_STATUS_ERROR_UNIMPLEMENTED),
         };
 
-        unsafe { slice::from_raw_parts(ptr as _, size) }
+        Ok(unsafe { slice::from_raw_parts(ptr as _, size) })
     }
-    fn map_mut(&mut self) -> &mut [u8] {
+    fn map_mut(&mut self) -> Result<&mut [u8], VAStatus> {
         let (ptr, size) = match self {
-            Buffer::CodedBufferSegment(_, cs) => {
+            Buffer::CodedBufferSegment(_, cs, _) => {
                 (cs as *mut _ as _, mem::size_of::<VACodedBufferSegment>())
             }
             Buffer::Surface { size, map, .. } => (map.as_ptr(), *size),
-            _ => todo!(),
+            Buffer::EncQp(qp) => (qp.as_mut_ptr(), qp.len()),
+            Buffer::Generic { data, .. } => (data.as_mut_ptr(), data.len()),
+            _ => return Err(VA_STATUS_ERROR_UNIMPLEMENTED),
         };
 
-        unsafe { slice::from_raw_parts_mut(ptr as _, size) }
+        Ok(unsafe { slice::from_raw_parts_mut(ptr as _, size) })
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if let Buffer::Surface { buf, size, map } = self {
+            // the application's own memory stays with the application
+            if !matches!(buf, SurfaceMemory::UserPtr) {
+                unsafe {
+                    let _ = munmap(map.as_ptr() as _, *size);
+                }
+            }
+        }
+    }
+}
+
+// where the pixels of a surface live
+#[derive(Debug)]
+enum SurfaceMemory {
+    Udmabuf(UDmabufAllocation),
+    // imported from the application
+    Dmabuf(OwnedFd),
+    UserPtr,
+}
+
+impl SurfaceMemory {
+    fn dmabuf(&self) -> Result<&OwnedFd, VAStatus> {
+        match self {
+            SurfaceMemory::Udmabuf(alloc) => Ok(&alloc.dmabuf),
+            SurfaceMemory::Dmabuf(fd) => Ok(fd),
+            SurfaceMemory::UserPtr => Err(VA_STATUS_ERROR_UNSUPPORTED_MEMORY_TYPE),
+        }
     }
 }
 
@@ -178,13 +328,20 @@ impl fmt::Debug for Buffer {
                 .debug_tuple("VppPipelineParameterBufferType")
                 .field(arg0)
                 .finish(),
-            Self::CodedBufferSegment(_, arg0) => {
-                f.debug_tuple("CodedBufferSegment").field(arg0).finish()
-            }
+            Self::CodedBufferSegment(_, arg0, arg1) => f
+                .debug_tuple("CodedBufferSegment")
+                .field(arg0)
+                .field(arg1)
+                .finish(),
             Self::EncSequenceParameter(arg0) => f.debug_tuple("EncSequenceParameter").finish(),
-            Self::EncMiscParameter(arg0) => f.debug_tuple("EncMiscParameter").field(arg0).finish(),
+            Self::EncMiscParameter { type_, data } => f
+                .debug_struct("EncMiscParameter")
+                .field("type_", type_)
+                .field("data", data)
+                .finish(),
             Self::EncSliceParameter(arg0) => f.debug_tuple("EncSliceParameter").finish(),
             Self::EncPictureParameter(arg0) => f.debug_tuple("EncPictureParameter").finish(),
+            Self::EncQp(arg0) => f.debug_tuple("EncQp").field(arg0).finish(),
             Self::Generic { mem_type, data } => f
                 .debug_struct("Generic")
                 .field("mem_type", mem_type)
@@ -194,25 +351,773 @@ impl fmt::Debug for Buffer {
     }
 }
 
-#[derive(Debug)]
+// What x264 reported for the frame in a coded buffer, see vaX264QueryCodedBufferStats
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CodedBufferStats {
+    pub frame_type: i32, // X264_TYPE_*: 1 IDR, 2 I, 3 P, 4 BREF, 5 B
+    pub average_qp: u32,
+    pub bits: u64,
+    pub psnr: [f64; 3], // Y, U, V
+    pub psnr_avg: f64,
+    pub ssim: f64,
+}
+
+#[derive(Debug, Clone)]
 struct PlaneInfo {
     pitch: usize,
     offset: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Surface {
     width: u32,
     height: u32,
     format: VAImageFormat,
     buffer_id: u32,
     planes: Vec<PlaneInfo>, // (pitch, offset)
+
+    // what the contents are, as far as we know. Set when VPP writes to the surface
+    color_standard: VAProcColorStandardType,
+    full_range: bool,
+}
+
+impl Surface {
+    // surfaces only ever get fourccs that have one
+    fn layout(&self) -> &'static Layout {
+        format::layout(self.format.fourcc).unwrap()
+    }
+
+    fn rt_format(&self) -> u32 {
+        self.layout().rt_format
+    }
+}
+
+// what a surface of a VA_RT_FORMAT_* is when the application doesn't say
+fn default_fourcc(format: u32) -> Option<u32> {
+    match format {
+        VA_RT_FORMAT_YUV420 => Some(VA_FOURCC_NV12),
+        VA_RT_FORMAT_YUV422 => Some(VA_FOURCC_YUY2),
+        VA_RT_FORMAT_YUV444 => Some(VA_FOURCC_444P),
+        VA_RT_FORMAT_YUV400 => Some(VA_FOURCC_Y800),
+        VA_RT_FORMAT_RGB16 => Some(VA_FOURCC_RGB565),
+        VA_RT_FORMAT_RGB32 => Some(VA_FOURCC_BGRX),
+        VA_RT_FORMAT_RGB32_10 => Some(VA_FOURCC_X2R10G10B10),
+        _ => None,
+    }
+}
+
+// x264::Encoder keeps the x264_t to itself, which we need for anything past plain encoding
+struct X264Encoder {
+    raw: NonNull<x264_t>,
+    width: i32,
+    height: i32,
+    frame_reference: i32, // the most it may search, what the application asked for
+    dpb_size: i32,        // how many frames back it keeps
+    search_refs: i32,     // how many it searches right now, see constrain_references
+    bframes: i32,
+}
+
+impl X264Encoder {
+    fn open(param: &mut x264_param_t) -> Result<Self, VAStatus> {
+        let raw = NonNull::new(unsafe { x264_encoder_open(param) })
+            .ok_or(VA_STATUS_ERROR_ENCODING_ERROR)?;
+
+        Ok(Self {
+            raw,
+            width: param.i_width,
+            height: param.i_height,
+            frame_reference: param.i_frame_reference,
+            dpb_size: param.i_dpb_size.max(param.i_frame_reference),
+            search_refs: param.i_frame_reference,
+            bframes: param.i_bframe,
+        })
+    }
+
+    fn encode(
+        &mut self,
+        pts: i64,
+        i_type: i32,
+        qp: Option<i32>,
+        quant_offsets: Option<&[f32]>,
+        image: x264::Image,
+    ) -> Result<(Vec<u8>, x264_picture_t), VAStatus> {
+        // the size is fixed once the encoder is open
+        if image.width() != self.width || image.height() != self.height {
+            return Err(VA_STATUS_ERROR_INVALID_SURFACE);
+        }
+
+        unsafe {
+            let mut pic_in = MaybeUninit::uninit();
+            x264_picture_init(pic_in.as_mut_ptr());
+            let mut pic_in = pic_in.assume_init();
+            pic_in.i_pts = pts;
+            pic_in.i_type = i_type;
+            if let Some(qp) = qp {
+                pic_in.i_qpplus1 = qp + 1;
+            }
+            pic_in.img = image.raw();
+
+            if let Some(offsets) = quant_offsets {
+                // x264 frees these itself once it's done with them
+                let p = malloc(offsets.len() * size_of::<f32>()) as *mut f32;
+                if p.is_null() {
+                    return Err(VA_STATUS_ERROR_ALLOCATION_FAILED);
+                }
+                p.copy_from_nonoverlapping(offsets.as_ptr(), offsets.len());
+                pic_in.prop.quant_offsets = p;
+                pic_in.prop.quant_offsets_free = Some(free);
+            }
+
+            self.encode_raw(&mut pic_in)
+        }
+    }
+
+    // the next of the frames x264 is still holding, see delayed_frames. After this there's no
+    // going back to encode() though, x264 takes no new frames once the stream is ended
+    fn flush(&mut self) -> Result<(Vec<u8>, x264_picture_t), VAStatus> {
+        unsafe { self.encode_raw(null_mut()) }
+    }
+
+    // frames x264 took but hasn't handed back yet, for lookahead, B-frames or frame threads
+    fn delayed_frames(&self) -> i32 {
+        unsafe { x264_encoder_delayed_frames(self.raw.as_ptr()) }
+    }
+
+    // a null picture ends the stream
+    unsafe fn encode_raw(
+        &mut self,
+        pic_in: *mut x264_picture_t,
+    ) -> Result<(Vec<u8>, x264_picture_t), VAStatus> {
+        let mut nals = null_mut();
+        let mut num_nals = 0;
+        let mut pic_out = MaybeUninit::zeroed();
+        let size = x264_encoder_encode(
+            self.raw.as_ptr(),
+            &mut nals,
+            &mut num_nals,
+            pic_in,
+            pic_out.as_mut_ptr(),
+        );
+        if size < 0 {
+            return Err(VA_STATUS_ERROR_ENCODING_ERROR);
+        }
+
+        // x264 lays the NAL payloads out back to back, so it's all one slice
+        let bitstream = if num_nals == 0 {
+            Vec::new()
+        } else {
+            slice::from_raw_parts((*nals).p_payload, size as usize).to_owned()
+        };
+
+        Ok((bitstream, pic_out.assume_init()))
+    }
+
+    // changes what x264 lets change mid-stream, i.e. rate control
+    fn reconfig(&mut self, f: impl FnOnce(&mut x264_param_t)) -> Result<(), VAStatus> {
+        unsafe {
+            let mut param = MaybeUninit::uninit();
+            x264_encoder_parameters(self.raw.as_ptr(), param.as_mut_ptr());
+            let mut param = param.assume_init();
+            f(&mut param);
+            match x264_encoder_reconfig(self.raw.as_ptr(), &mut param) {
+                0 => Ok(()),
+                _ => Err(VA_STATUS_ERROR_INVALID_PARAMETER),
+            }
+        }
+    }
+
+    // stop x264 from referencing any frame from `pts` onwards
+    fn invalidate_reference(&mut self, pts: i64) -> Result<(), VAStatus> {
+        match unsafe { x264_encoder_invalidate_reference(self.raw.as_ptr(), pts) } {
+            0 => Ok(()),
+            _ => Err(VA_STATUS_ERROR_ENCODING_ERROR),
+        }
+    }
+
+    // how many of the frames it keeps x264 may search, no more than it was opened with
+    fn search_references(&mut self, count: i32) -> Result<(), VAStatus> {
+        let count = count.clamp(1, self.frame_reference);
+        if count != self.search_refs {
+            self.reconfig(|param| param.i_frame_reference = count)?;
+            self.search_refs = count;
+        }
+        Ok(())
+    }
+}
+
+// RGB to YUV through dcp, which only reads BGRA and ARGB, and only writes NV12, I420 and I444.
+// Other RGB is unpacked to BGRA first. YV12 is I420 with the chroma planes the other way round,
+// and everything else goes through I444
+fn convert_from_rgb(
+    src: &Surface,
+    src_map: &[u8],
+    color_space: dcp::ColorSpace,
+    dst: &Surface,
+    dst_map: &mut [u8],
+) -> Result<(), VAStatus> {
+    let (width, height) = (dst.width, dst.height);
+    let unpacked;
+    let (src_format, src_pitch, src_map) = match src.format.fourcc {
+        VA_FOURCC_BGRA | VA_FOURCC_BGRX => (PixelFormat::Bgra, src.planes[0].pitch, src_map),
+        VA_FOURCC_ARGB | VA_FOURCC_XRGB => (PixelFormat::Argb, src.planes[0].pitch, src_map),
+        _ => {
+            unpacked = format::unpack_rgb(width, height, src.layout(), &src.planes[0], src_map);
+            (PixelFormat::Bgra, width as usize * 4, &unpacked[..])
+        }
+    };
+    let convert = |pixel_format, planes: &[PlaneInfo], map: &mut [u8]| {
+        let mut buffers = format::split_planes_mut(map, planes);
+        let mut strides: Vec<_> = planes.iter().map(|plane| plane.pitch).collect();
+        if dst.format.fourcc == VA_FOURCC_YV12 {
+            buffers.swap(1, 2);
+            strides.swap(1, 2);
+        }
+
+        convert_image(
+            width,
+            height,
+            &ImageFormat {
+                pixel_format: src_format,
+                color_space: dcp::ColorSpace::Rgb,
+                num_planes: 1,
+            },
+            Some(&[src_pitch]),
+            &[src_map],
+            &ImageFormat {
+                pixel_format,
+                color_space,
+                num_planes: buffers.len() as u32,
+            },
+            Some(&strides[..]),
+            &mut buffers,
+        )
+        .map_err(|_| VA_STATUS_ERROR_OPERATION_FAILED)
+    };
+
+    match dst.format.fourcc {
+        VA_FOURCC_NV12 => convert(PixelFormat::Nv12, &dst.planes, dst_map),
+        VA_FOURCC_I420 | VA_FOURCC_YV12 => convert(PixelFormat::I420, &dst.planes, dst_map),
+        VA_FOURCC_444P => convert(PixelFormat::I444, &dst.planes, dst_map),
+        _ => {
+            let i444 = format::layout(VA_FOURCC_444P).unwrap();
+            let (planes, size) = i444.allocate(width, height, 1);
+            let mut scratch = vec![0; size];
+            convert(PixelFormat::I444, &planes, &mut scratch)?;
+            format::convert(
+                width,
+                height,
+                i444,
+                &planes,
+                &scratch,
+                dst.layout(),
+                &dst.planes,
+                dst_map,
+            );
+            Ok(())
+        }
+    }
+}
+
+// copies the reconstructed frame x264 hands back in x264_picture_t::img, which is always NV12,
+// into a YUV surface
+fn copy_recon(img: &x264_image_t, width: usize, height: usize, surface: &Surface, map: &mut [u8]) {
+    // x264 keeps its reference frames padded out to whole macroblocks
+    let width = surface.width.min(align_up(width, 16) as u32) as usize;
+    let height = surface.height.min(align_up(height, 16) as u32) as usize;
+
+    let copy = |planes: &[PlaneInfo], map: &mut [u8]| {
+        // luma, then interleaved chroma at half height
+        let sizes = [(width, height), (align_up(width, 2), height.div_ceil(2))];
+        for (i, (row_bytes, rows)) in sizes.into_iter().enumerate() {
+            let src_stride = img.i_stride[i] as usize;
+            let PlaneInfo { pitch, offset } = planes[i];
+
+            for row in 0..rows {
+                let src =
+                    unsafe { slice::from_raw_parts(img.plane[i].add(row * src_stride), row_bytes) };
+                map[offset + row * pitch..][..row_bytes].copy_from_slice(src);
+            }
+        }
+    };
+
+    if surface.format.fourcc == VA_FOURCC_NV12 {
+        copy(&surface.planes, map);
+    } else {
+        // anything else goes through a packed NV12 copy
+        let nv12 = format::layout(VA_FOURCC_NV12).unwrap();
+        let (planes, size) = nv12.allocate(width as u32, height as u32, 1);
+        let mut scratch = vec![0; size];
+        copy(&planes, &mut scratch);
+        format::convert(
+            width as u32,
+            height as u32,
+            nv12,
+            &planes,
+            &scratch,
+            surface.layout(),
+            &surface.planes,
+            map,
+        );
+    }
+}
+
+impl Drop for X264Encoder {
+    fn drop(&mut self) {
+        unsafe { x264_encoder_close(self.raw.as_ptr()) }
+    }
+}
+
+// One context's rate control. The mode comes from the config, the rest from
+// VAEncMiscParameterRateControl and VAEncMiscParameterHRD, which may change mid-stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RateControl {
+    mode: u32,            // VA_RC_*
+    bits_per_second: u32, // 0 until the sequence or a rate control buffer says
+    target_percentage: u32,
+    window_size: u32, // ms
+    min_qp: u32,
+    max_qp: u32,
+    hrd: Option<(u32, u32)>, // (buffer_size, initial_buffer_fullness) in bits
+}
+
+impl Default for RateControl {
+    fn default() -> Self {
+        Self {
+            mode: VA_RC_CBR,
+            bits_per_second: 0,
+            target_percentage: 100,
+            window_size: 1000,
+            min_qp: 0,
+            max_qp: 0,
+            hrd: None,
+        }
+    }
+}
+
+impl RateControl {
+    // what x264's ABR aims for, in bits per second. Nothing with CQP
+    fn target_bitrate(&self) -> u64 {
+        let bits_per_second = u64::from(self.bits_per_second);
+        match self.mode {
+            VA_RC_CQP => 0,
+            VA_RC_VBR => bits_per_second * u64::from(self.target_percentage) / 100,
+            _ => bits_per_second,
+        }
+    }
+
+    fn apply(&self, param: &mut x264_param_t) {
+        let rc = &mut param.rc;
+        if self.mode == VA_RC_CQP {
+            // the QP comes with every picture. x264's own CQP turns AQ off, and x264 only looks at
+            // quant_offsets with AQ on, so it's CRF with every frame's QP forced instead. AQ at
+            // zero strength moves nothing but the QP map's macroblocks
+            rc.i_rc_method = X264_RC_CRF as i32;
+            rc.b_mb_tree = 0;
+            rc.i_aq_mode = X264_AQ_VARIANCE as i32;
+            rc.f_aq_strength = 0.0;
+            return;
+        }
+        if self.bits_per_second == 0 {
+            // nothing to aim for, leave it to the preset
+            return;
+        }
+
+        // x264 counts in kbit, of 1000 bits
+        let kbps = |bits: u64| i32::try_from(bits / 1000).unwrap_or(i32::MAX);
+        let max_bitrate = u64::from(self.bits_per_second);
+        rc.i_rc_method = X264_RC_ABR as i32;
+        rc.i_bitrate = kbps(self.target_bitrate());
+        rc.i_vbv_max_bitrate = kbps(max_bitrate);
+        match self.hrd {
+            Some((buffer_size, initial_fullness)) if buffer_size != 0 => {
+                rc.i_vbv_buffer_size = kbps(buffer_size.into());
+                rc.f_vbv_buffer_init = initial_fullness as f32 / buffer_size as f32;
+            }
+            // no HRD, the buffer is the rate control window
+            _ => rc.i_vbv_buffer_size = kbps(max_bitrate * u64::from(self.window_size) / 1000),
+        }
+
+        if self.min_qp != 0 {
+            rc.i_qp_min = self.min_qp as i32;
+        }
+        if self.max_qp != 0 {
+            rc.i_qp_max = self.max_qp as i32;
+        }
+    }
 }
 
 #[derive(Default)]
 struct EncData {
-    enc: Option<Encoder>,
+    enc: Option<X264Encoder>,
+    rc: RateControl,
+    rc_changed: bool, // since the encoder was opened
+    seq: Option<VAEncSequenceParameterBufferH264>,
+    // the picture being rendered, consumed by end_picture
     coded_buf: Option<VABufferID>,
+    pic: Option<VAEncPictureParameterBufferH264>,
+    slice: Option<VAEncSliceParameterBufferH264>,
+    fps: Option<(u32, u32)>, // (num, den), from VAEncMiscParameterFrameRate
+    pts: i64,                // one tick per frame
+    refs: HashMap<VASurfaceID, i64>, // reconstructed surface -> pts of the frame in it
+    qp_map: Option<Vec<u8>>, // from VAEncQPBufferType, for the next picture only
+    // (skip_frame_flag, num_skip_frames, size_skip_frames), for the next picture only
+    skip_frame: Option<(u8, u8, u32)>,
+    skipped_bits: u64, // from size_skip_frames, not taken off the clock yet, see skipped_time
+    // frames x264 is still holding, see encoder(): (pts, render target, CurrPic)
+    held: Vec<(i64, VASurfaceID, VASurfaceID)>,
+    // and their coded buffers in the order they came in. Whatever x264 hands back next goes in
+    // the first, so the coded buffers stay in decode order even with B-frames
+    coded_bufs: VecDeque<VABufferID>,
+}
+
+impl EncData {
+    fn fps(&self) -> Option<(u32, u32)> {
+        if self.fps.is_some() {
+            return self.fps;
+        }
+
+        let seq = self.seq.as_ref()?;
+        let timing_info_present = unsafe { seq.vui_fields.bits.timing_info_present_flag() } != 0;
+        if seq.vui_parameters_present_flag != 0
+            && timing_info_present
+            && seq.num_units_in_tick != 0
+            && seq.time_scale != 0
+        {
+            // time_scale counts fields, so a frame is two ticks
+            Some((seq.time_scale, seq.num_units_in_tick * 2))
+        } else {
+            None
+        }
+    }
+
+    // how far the clock moves for frames the application skipped and wrote itself. Rate control
+    // goes by time (see encoder()), so they get their time, less as many frames' worth as they
+    // took in bits: x264 can't be told about bits it didn't write. Whatever doesn't make up a
+    // whole frame waits for the next skip
+    fn skipped_time(&mut self, num_skip_frames: u8, size_skip_frames: u32) -> i64 {
+        let (num, den) = self.fps().unwrap_or((25, 1)); // x264's default
+        let bits_per_frame = self.rc.target_bitrate() * u64::from(den) / u64::from(num.max(1));
+        if bits_per_frame == 0 {
+            self.skipped_bits = 0;
+            return num_skip_frames.into();
+        }
+
+        self.skipped_bits += u64::from(size_skip_frames);
+        let spent = (self.skipped_bits / bits_per_frame).min(num_skip_frames.into());
+        self.skipped_bits -= spent * bits_per_frame;
+        (u64::from(num_skip_frames) - spent) as i64
+    }
+
+    fn update_rc(&mut self, f: impl FnOnce(&mut RateControl)) {
+        let old = self.rc;
+        f(&mut self.rc);
+        self.rc_changed |= self.rc != old;
+    }
+
+    fn encoder(
+        &mut self,
+        config: &DriverConfig,
+        render_target: &Surface,
+    ) -> Result<&mut X264Encoder, VAStatus> {
+        if self.enc.is_none() {
+            let seq = self.seq.as_ref().ok_or(VA_STATUS_ERROR_INVALID_PARAMETER)?;
+
+            // x264::Setup doesn't expose VUI, so fill out the params ourselves
+            let mut param = unsafe {
+                let mut param = MaybeUninit::<x264_param_t>::uninit();
+                let res = x264_param_default_preset(
+                    param.as_mut_ptr(),
+                    config.preset.to_cstr(),
+                    config.tune.to_cstr(config.fast_decode, config.zero_latency),
+                );
+                if res != 0 {
+                    return Err(VA_STATUS_ERROR_INVALID_PARAMETER);
+                }
+                param.assume_init()
+            };
+
+            if let Some(threads) = config.threads {
+                param.i_threads = threads;
+            }
+            // see DriverConfig::settle for when frame threads are allowed
+            if let Some(sliced_threads) = config.sliced_threads {
+                param.b_sliced_threads = sliced_threads as i32;
+            }
+            // with zerolatency the tune has already turned off everything that holds frames
+            // back (B-frames, lookahead, frame threads), so every frame comes back out of the
+            // vaEndPicture that put it in. Otherwise the preset's settings stand, and a sync on a
+            // frame x264 still has ends the stream early, see Driver::drain
+
+            // the sequence's bitrate holds until a rate control buffer says otherwise
+            if self.rc.bits_per_second == 0 {
+                self.rc.bits_per_second = seq.bits_per_second;
+            }
+            self.rc.apply(&mut param);
+            self.rc_changed = false;
+            // keep around as many frames as the application may want to go back to. Some of them
+            // may be long-term ones, which x264 can't mark as such, only keep by keeping every
+            // frame that long. How many of those it searches goes by what each picture names
+            param.i_frame_reference = (seq.max_num_ref_frames as i32).clamp(1, MAX_DPB);
+            if param.i_frame_reference > 1 {
+                param.i_dpb_size = MAX_DPB;
+            }
+            // for CodedBufferStats
+            param.analyse.b_psnr = 1;
+            param.analyse.b_ssim = 1;
+            if let Some((num, den)) = self.fps() {
+                // pts goes up by one every frame, so the timebase is one frame long
+                param.i_fps_num = num;
+                param.i_fps_den = den;
+                param.i_timebase_num = den;
+                param.i_timebase_den = num;
+            }
+
+            // rate control goes by timestamps, which is how frames the application skipped still
+            // count. x264 leaves fixed_frame_rate_flag out of the VUI then, even if the sequence
+            // has it, but with frames skipped the rate isn't fixed anyway
+            param.b_vfr_input = 1;
+            let vui = unsafe { seq.vui_fields.bits };
+
+            if seq.vui_parameters_present_flag != 0 {
+                if vui.aspect_ratio_info_present_flag() != 0 {
+                    let (sar_width, sar_height) = match seq.aspect_ratio_idc {
+                        EXTENDED_SAR => (seq.sar_width as i32, seq.sar_height as i32),
+                        idc => SAR_TABLE.get(idc as usize).copied().unwrap_or((0, 0)),
+                    };
+                    param.vui.i_sar_width = sar_width;
+                    param.vui.i_sar_height = sar_height;
+                }
+            }
+
+            if let Some((primaries, transfer, matrix)) =
+                vui_color_description(render_target.color_standard)
+            {
+                param.vui.i_colorprim = primaries;
+                param.vui.i_transfer = transfer;
+                param.vui.i_colmatrix = matrix;
+            }
+            param.vui.b_fullrange = render_target.full_range as i32;
+
+            // what encode_picture feeds it, see there
+            param.i_csp = Encoding::from(match render_target.format.fourcc {
+                _ if render_target.layout().yuv.is_empty() => {
+                    return Err(VA_STATUS_ERROR_UNSUPPORTED_RT_FORMAT)
+                }
+                VA_FOURCC_NV12 => Colorspace::NV12,
+                VA_FOURCC_YV12 => Colorspace::YV12,
+                _ => Colorspace::I420,
+            })
+            .into_raw();
+
+            // the coded size comes in macroblocks, and the surface may be bigger or smaller than
+            // that. Only encode what both cover, x264 crops the padding up to 16 on its own
+            let coded_width = match seq.picture_width_in_mbs {
+                0 => align_up(render_target.width as usize, 16) as u32,
+                mbs => u32::from(mbs) * 16,
+            };
+            let coded_height = match seq.picture_height_in_mbs {
+                0 => align_up(render_target.height as usize, 16) as u32,
+                mbs => u32::from(mbs) * 16,
+            };
+            let width = render_target.width.min(coded_width);
+            let height = render_target.height.min(coded_height);
+
+            if seq.frame_cropping_flag != 0 {
+                // 4:2:0, so offsets are in units of 2 pixels (and 4 rows for interlaced)
+                let frame_mbs_only = unsafe { seq.seq_fields.bits.frame_mbs_only_flag() };
+                let crop_unit_x = 2;
+                let crop_unit_y = 2 * (2 - frame_mbs_only);
+
+                let left = seq.frame_crop_left_offset * crop_unit_x;
+                let right = seq.frame_crop_right_offset * crop_unit_x;
+                let top = seq.frame_crop_top_offset * crop_unit_y;
+                let bottom = seq.frame_crop_bottom_offset * crop_unit_y;
+                if left + right >= coded_width || top + bottom >= coded_height {
+                    return Err(VA_STATUS_ERROR_INVALID_PARAMETER);
+                }
+
+                // x264 measures the right and bottom crop from the input size, not the coded size
+                param.crop_rect.i_left = left as i32;
+                param.crop_rect.i_top = top as i32;
+                param.crop_rect.i_right = width.saturating_sub(coded_width - right) as i32;
+                param.crop_rect.i_bottom = height.saturating_sub(coded_height - bottom) as i32;
+            }
+
+            param.i_width = width
+                .try_into()
+                .map_err(|_| VA_STATUS_ERROR_RESOLUTION_NOT_SUPPORTED)?;
+            param.i_height = height
+                .try_into()
+                .map_err(|_| VA_STATUS_ERROR_RESOLUTION_NOT_SUPPORTED)?;
+
+            // goes last, it undoes anything the profile doesn't allow
+            if let Some(profile) = config.profile {
+                if unsafe { x264_param_apply_profile(&mut param, profile.to_cstr()) } < 0 {
+                    return Err(VA_STATUS_ERROR_INVALID_PARAMETER);
+                }
+            }
+
+            self.enc = Some(X264Encoder::open(&mut param)?);
+        }
+
+        Ok(self.enc.as_mut().unwrap())
+    }
+
+    // Turns the QP map for this picture into offsets from the frame's QP, which is what x264
+    // takes. Only CQP has maps, see render_picture, so the frame's QP is the one forced on it
+    fn quant_offsets(&mut self, qp: Option<i32>) -> Result<Option<Vec<f32>>, VAStatus> {
+        let (Some(qp_map), Some(qp)) = (self.qp_map.take(), qp) else {
+            return Ok(None);
+        };
+        let enc = self.enc.as_ref().ok_or(VA_STATUS_ERROR_INVALID_CONTEXT)?;
+
+        let mb_count = ((enc.width as usize + 15) / 16) * ((enc.height as usize + 15) / 16);
+        if qp_map.len() < mb_count {
+            return Err(VA_STATUS_ERROR_INVALID_PARAMETER);
+        }
+
+        Ok(Some(
+            qp_map[..mb_count]
+                .iter()
+                .map(|&mb_qp| f32::from(mb_qp) - qp as f32)
+                .collect(),
+        ))
+    }
+
+    // Keeps x264 to the reference frames the application named for this picture, as far as
+    // x264 allows: it can be told to forget frames newer than a given one and how many to search,
+    // but can't be made to pick a particular one. If it no longer has any of them the picture
+    // becomes an IDR. Returns the frame type to ask x264 for
+    fn constrain_references(
+        &mut self,
+        pic: &VAEncPictureParameterBufferH264,
+        slice: &VAEncSliceParameterBufferH264,
+        pts: i64,
+    ) -> Result<i32, VAStatus> {
+        if unsafe { pic.pic_fields.bits.idr_pic_flag() } != 0 {
+            self.refs.clear();
+            return Ok(X264_TYPE_IDR as i32);
+        }
+        match slice.slice_type % 5 {
+            // x264 picks its own B-frames, if any, so nothing to put in RefPicList1
+            SLICE_TYPE_B => return Err(VA_STATUS_ERROR_UNIMPLEMENTED),
+            SLICE_TYPE_I => return Ok(X264_TYPE_I as i32),
+            _ => {}
+        }
+
+        let valid = |p: &&VAPictureH264| {
+            p.picture_id != VA_INVALID_ID && p.flags & VA_PICTURE_H264_INVALID == 0
+        };
+
+        // RefPicList0 wins if there is one, otherwise anything in the DPB will do
+        let num_active = if slice.num_ref_idx_active_override_flag != 0 {
+            slice.num_ref_idx_l0_active_minus1
+        } else {
+            pic.num_ref_idx_l0_active_minus1
+        } as usize
+            + 1;
+        let mut named: Vec<&VAPictureH264> = slice.RefPicList0[..num_active.min(32)]
+            .iter()
+            .filter(valid)
+            .collect();
+        if named.is_empty() {
+            named = pic.ReferenceFrames.iter().filter(valid).collect();
+        }
+
+        let enc = self.enc.as_mut().ok_or(VA_STATUS_ERROR_INVALID_CONTEXT)?;
+        // with B-frames x264 reorders, and won't forget frames either. It's on its own
+        if enc.bframes > 0 {
+            return Ok(X264_TYPE_AUTO as i32);
+        }
+        if named.is_empty() {
+            // application isn't managing references, leave it all to x264
+            enc.search_references(enc.frame_reference)?;
+            return Ok(X264_TYPE_AUTO as i32);
+        }
+
+        // x264 keeps the last dpb_size frames, long-term references included. Whatever's older,
+        // or was forgotten for an earlier picture, it can't go back to
+        let oldest_kept = pts - i64::from(enc.dpb_size);
+        let kept: Vec<i64> = named
+            .iter()
+            .filter_map(|p| self.refs.get(&p.picture_id))
+            .filter(|&&p| p >= oldest_kept)
+            .copied()
+            .collect();
+        // an IDR needs no references at all, which is the closest there is
+        let Some(&newest) = kept.iter().max() else {
+            if named
+                .iter()
+                .any(|p| p.flags & VA_PICTURE_H264_LONG_TERM_REFERENCE != 0)
+            {
+                debug!("long-term references only last {} frames", enc.dpb_size);
+            }
+            self.refs.clear();
+            return Ok(X264_TYPE_IDR as i32);
+        };
+
+        if newest < pts - 1 {
+            if enc.invalidate_reference(newest + 1).is_err() {
+                self.refs.clear();
+                return Ok(X264_TYPE_IDR as i32);
+            }
+            self.refs.retain(|_, p| *p <= newest);
+        }
+        // the newest named one and the ones just before it. That's all of them if they're the
+        // latest the application has, which they are unless it's skipping some
+        enc.search_references(kept.len() as i32)?;
+
+        Ok(X264_TYPE_AUTO as i32)
+    }
+}
+
+// the highest H.264 allows, see encode_picture
+const SKIP_QP: i32 = 51;
+// reference frames H.264 allows, short and long-term together
+const MAX_DPB: i32 = 16;
+
+// slice_type in VAEncSliceParameterBufferH264, plus 5 if every slice is the same
+const SLICE_TYPE_B: u8 = 1;
+const SLICE_TYPE_I: u8 = 2;
+
+const EXTENDED_SAR: u8 = 255;
+
+// sample aspect ratios for each aspect_ratio_idc, table E-1 in the H.264 spec
+const SAR_TABLE: [(i32, i32); 17] = [
+    (0, 0),
+    (1, 1),
+    (12, 11),
+    (10, 11),
+    (16, 11),
+    (40, 33),
+    (24, 11),
+    (20, 11),
+    (32, 11),
+    (80, 33),
+    (18, 11),
+    (15, 11),
+    (64, 33),
+    (160, 99),
+    (4, 3),
+    (3, 2),
+    (2, 1),
+];
+
+// (colour_primaries, transfer_characteristics, matrix_coefficients) as written in the VUI
+fn vui_color_description(standard: VAProcColorStandardType) -> Option<(i32, i32, i32)> {
+    Some(match standard {
+        _VAProcColorStandardType_VAProcColorStandardBT601
+        | _VAProcColorStandardType_VAProcColorStandardSMPTE170M => (6, 6, 6),
+        _VAProcColorStandardType_VAProcColorStandardBT709 => (1, 1, 1),
+        _VAProcColorStandardType_VAProcColorStandardBT470M => (4, 4, 4),
+        _VAProcColorStandardType_VAProcColorStandardBT470BG => (5, 5, 5),
+        _VAProcColorStandardType_VAProcColorStandardSMPTE240M => (7, 7, 7),
+        _VAProcColorStandardType_VAProcColorStandardXVYCC601 => (6, 11, 6),
+        _VAProcColorStandardType_VAProcColorStandardXVYCC709 => (1, 11, 1),
+        _VAProcColorStandardType_VAProcColorStandardBT2020 => (9, 14, 9),
+        _ => return None,
+    })
 }
 
 enum ContextData {
@@ -251,11 +1156,14 @@ struct Driver {
     // egl_display: Display,
     // gbm: Device<OwnedFd>,
     udma: UDmaBuf,
-    surfaces: Vec<Option<Surface>>,
-    configs: Vec<Option<Config>>,
-    contexts: Vec<Option<Context>>,
-    images: Vec<Option<Image>>,
-    buffers: Vec<Option<Buffer>>,
+    config: DriverConfig,
+    // see table.rs for how these are locked
+    surfaces: Table<Surface>,
+    configs: Table<Config>,
+    contexts: Table<Context>,
+    images: Table<Image>,
+    buffers: Table<Buffer>,
+    trace: Option<Mutex<Recorder>>,
     // egl_ctx: khronos_egl::Context,
     // egl_export_dmabuf_image_mesa: unsafe extern "C" fn(display: EGLDisplay,
     //                                     image: EGLImage,
@@ -264,10 +1172,70 @@ struct Driver {
     // 				offset: *mut c_int) -> Boolean,
 }
 
+// Every entry point runs under this: a panic unwinding into libva would take the whole process
+// down, so it fails the call instead. `args` is only formatted when debug logging is on, after the
+// call so it can show what came back through output pointers too
+fn guard(
+    name: &str,
+    args: impl FnOnce(VAStatus) -> String,
+    f: impl FnOnce() -> VAStatus,
+) -> VAStatus {
+    let status = panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|e| {
+        let msg = e
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| e.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("?");
+        error!("{name} panicked: {msg}");
+        VA_STATUS_ERROR_OPERATION_FAILED
+    });
+    debug!("{name}({}) = {status:#x}", args(status));
+    status
+}
+
+// an output parameter in a log line, which only holds anything once the call has worked
+struct Out<T>(VAStatus, *const T);
+
+impl<T: fmt::Display> fmt::Display for Out<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Out(VA_STATUS_SUCCESS, p) if !p.is_null() => unsafe { (**p).fmt(f) },
+            _ => f.write_str("-"),
+        }
+    }
+}
+
+// An array the application passed in, `len` long. A negative length or a null array with
+// anything in it is the application's mistake, and has to be an error before it's a slice
+unsafe fn app_slice<'a, T>(ptr: *const T, len: impl TryInto<usize>) -> Result<&'a [T], VAStatus> {
+    match len.try_into() {
+        Ok(0) => Ok(&[]),
+        Ok(len) if !ptr.is_null() => Ok(slice::from_raw_parts(ptr, len)),
+        _ => Err(VA_STATUS_ERROR_INVALID_PARAMETER),
+    }
+}
+
+unsafe fn app_slice_mut<'a, T>(
+    ptr: *mut T,
+    len: impl TryInto<usize>,
+) -> Result<&'a mut [T], VAStatus> {
+    match len.try_into() {
+        Ok(0) => Ok(&mut []),
+        Ok(len) if !ptr.is_null() => Ok(slice::from_raw_parts_mut(ptr, len)),
+        _ => Err(VA_STATUS_ERROR_INVALID_PARAMETER),
+    }
+}
+
 unsafe extern "C" fn terminate(ctx: VADriverContextP) -> VAStatus {
-    drop(Box::from_raw((*ctx).pDriverData as *mut Driver));
+    guard(
+        "vaTerminate",
+        |_| String::new(),
+        || {
+            drop(Box::from_raw((*ctx).pDriverData as *mut Driver));
 
-    VA_STATUS_SUCCESS
+            VA_STATUS_SUCCESS
+        },
+    )
 }
 
 unsafe extern "C" fn query_config_profiles(
@@ -275,14 +1243,17 @@ unsafe extern "C" fn query_config_profiles(
     profile_list: *mut VAProfile,
     num_profiles: *mut c_int,
 ) -> VAStatus {
-    let profile_list = slice::from_raw_parts_mut(profile_list, NUM_PROFILES);
-    // profile_list[0] = VAProfile_VAProfileH264Baseline;
-    profile_list[0] = VAProfile_VAProfileH264Main;
-    // profile_list[2] = VAProfile_VAProfileH264High;
+    guard(
+        "vaQueryConfigProfiles",
+        |status| format!("num_profiles={}", Out(status, num_profiles)),
+        || {
+            let profiles = caps::profiles();
+            slice::from_raw_parts_mut(profile_list, profiles.len()).copy_from_slice(&profiles);
+            *num_profiles = profiles.len() as c_int;
 
-    *num_profiles = 1;
-
-    VA_STATUS_SUCCESS
+            VA_STATUS_SUCCESS
+        },
+    )
 }
 
 unsafe extern "C" fn query_config_entrypoints(
@@ -291,17 +1262,26 @@ unsafe extern "C" fn query_config_entrypoints(
     entrypoint_list: *mut VAEntrypoint,
     num_entrypoints: *mut c_int,
 ) -> VAStatus {
-    match profile {
-        VAProfile_VAProfileH264Main => {
-            *entrypoint_list = VAEntrypoint_VAEntrypointEncPicture;
-            *num_entrypoints = 1;
-        }
-        _ => {
-            *num_entrypoints = 0;
-        }
-    }
+    guard(
+        "vaQueryConfigEntrypoints",
+        |status| {
+            format!(
+                "profile={profile} num_entrypoints={}",
+                Out(status, num_entrypoints)
+            )
+        },
+        || {
+            let entrypoints = caps::entrypoints(profile);
+            if entrypoints.is_empty() {
+                return VA_STATUS_ERROR_UNSUPPORTED_PROFILE;
+            }
 
-    VA_STATUS_SUCCESS
+            slice::from_raw_parts_mut(entrypoint_list, entrypoints.len())
+                .copy_from_slice(&entrypoints);
+            *num_entrypoints = entrypoints.len() as c_int;
+            VA_STATUS_SUCCESS
+        },
+    )
 }
 
 unsafe extern "C" fn query_config_attributes(
@@ -312,7 +1292,30 @@ unsafe extern "C" fn query_config_attributes(
     attrib_list: *mut VAConfigAttrib,
     num_attribs: *mut c_int,
 ) -> VAStatus {
-    todo!()
+    guard(
+        "vaQueryConfigAttributes",
+        |status| {
+            format!(
+                "config={config_id} num_attribs={}",
+                Out(status, num_attribs)
+            )
+        },
+        || {
+            let driver = &*((*ctx).pDriverData as *const Driver);
+
+            match driver.query_config_attributes(config_id) {
+                Ok((p, e, attribs)) => {
+                    *profile = p;
+                    *entrypoint = e;
+                    // the application sized attrib_list by max_attributes
+                    slice::from_raw_parts_mut(attrib_list, attribs.len()).copy_from_slice(&attribs);
+                    *num_attribs = attribs.len() as c_int;
+                    VA_STATUS_SUCCESS
+                }
+                Err(e) => e,
+            }
+        },
+    )
 }
 
 unsafe extern "C" fn create_config(
@@ -323,26 +1326,43 @@ unsafe extern "C" fn create_config(
     num_attribs: c_int,
     config_id: *mut VAConfigID,
 ) -> VAStatus {
-    let driver = &mut *((*ctx).pDriverData as *mut Driver);
-
-    match driver.create_config(
-        profile,
-        entrypoint,
-        slice::from_raw_parts(attrib_list, num_attribs as usize),
-    ) {
-        Ok(cid) => {
-            *config_id = cid;
-            VA_STATUS_SUCCESS
-        }
-        Err(e) => e,
-    }
+    guard(
+        "vaCreateConfig",
+        |status| {
+            format!(
+                "profile={profile} entrypoint={entrypoint} num_attribs={num_attribs} config={}",
+                Out(status, config_id)
+            )
+        },
+        || {
+            let driver = &*((*ctx).pDriverData as *const Driver);
+
+            match app_slice(attrib_list, num_attribs)
+                .and_then(|attribs| driver.create_config(profile, entrypoint, attribs))
+            {
+                Ok(cid) => {
+                    *config_id = cid;
+                    VA_STATUS_SUCCESS
+                }
+                Err(e) => e,
+            }
+        },
+    )
 }
 
 unsafe extern "C" fn destroy_config(ctx: VADriverContextP, config_id: VAConfigID) -> VAStatus {
-    let driver = &mut *((*ctx).pDriverData as *mut Driver);
-    *driver.configs.get_mut(config_id as usize).unwrap() = None;
-
-    VA_STATUS_SUCCESS
+    guard(
+        "vaDestroyConfig",
+        |_| format!("config={config_id}"),
+        || {
+            let driver = &*((*ctx).pDriverData as *const Driver);
+
+            match driver.destroy_config(config_id) {
+                Ok(_) => VA_STATUS_SUCCESS,
+                Err(e) => e,
+            }
+        },
+    )
 }
 
 unsafe extern "C" fn get_config_attributes(
@@ -352,14 +1372,20 @@ unsafe extern "C" fn get_config_attributes(
     attrib_list: *mut VAConfigAttrib,
     num_attribs: c_int,
 ) -> VAStatus {
-    let driver = &mut *((*ctx).pDriverData as *mut Driver);
-
-    driver.get_config_attributes(
-        profile,
-        entrypoint,
-        slice::from_raw_parts_mut(attrib_list, num_attribs as usize),
-    );
-    VA_STATUS_SUCCESS
+    guard(
+        "vaGetConfigAttributes",
+        |_| format!("profile={profile} entrypoint={entrypoint} num_attribs={num_attribs}"),
+        || {
+            let driver = &*((*ctx).pDriverData as *const Driver);
+
+            match app_slice_mut(attrib_list, num_attribs)
+                .and_then(|attribs| driver.get_config_attributes(profile, entrypoint, attribs))
+            {
+                Ok(_) => VA_STATUS_SUCCESS,
+                Err(e) => e,
+            }
+        },
+    )
 }
 
 unsafe extern "C" fn create_surfaces(
@@ -370,7 +1396,21 @@ unsafe extern "C" fn create_surfaces(
     num_surfaces: c_int,
     surfaces: *mut VASurfaceID,
 ) -> VAStatus {
-    todo!()
+    guard(
+        "vaCreateSurfaces",
+        |_| format!("{width}x{height} format={format:#x} num_surfaces={num_surfaces}"),
+        || {
+            let driver = &*((*ctx).pDriverData as *const Driver);
+
+            // the same as vaCreateSurfaces2 without attributes, so the fourcc follows the format
+            match app_slice_mut(surfaces, num_surfaces).and_then(|surfaces| {
+                driver.create_surfaces(format as u32, width as u32, height as u32, surfaces, &[])
+            }) {
+                Ok(_) => VA_STATUS_SUCCESS,
+                Err(e) => e,
+            }
+        },
+    )
 }
 
 unsafe extern "C" fn destroy_surfaces(
@@ -378,12 +1418,20 @@ unsafe extern "C" fn destroy_surfaces(
     surface_list: *mut VASurfaceID,
     num_surfaces: c_int,
 ) -> VAStatus {
-    let driver = &mut *((*ctx).pDriverData as *mut Driver);
-
-    match driver.destroy_surfaces(slice::from_raw_parts(surface_list, num_surfaces as usize)) {
-        Ok(_) => VA_STATUS_SUCCESS,
-        Err(e) => e,
-    }
+    guard(
+        "vaDestroySurfaces",
+        |_| format!("num_surfaces={num_surfaces}"),
+        || {
+            let driver = &*((*ctx).pDriverData as *const Driver);
+
+            match app_slice(surface_list, num_surfaces)
+                .and_then(|surfaces| driver.destroy_surfaces(surfaces))
+            {
+                Ok(_) => VA_STATUS_SUCCESS,
+                Err(e) => e,
+            }
+        },
+    )
 }
 
 unsafe extern "C" fn create_context(
@@ -396,33 +1444,50 @@ unsafe extern "C" fn create_context(
     num_render_targets: c_int,
     context: *mut VAContextID,
 ) -> VAStatus {
-    let driver = &mut *((*ctx).pDriverData as *mut Driver);
-
-    match driver.create_context(
-        config_id,
-        picture_width,
-        picture_height,
-        flag,
-        slice::from_raw_parts(render_targets, num_render_targets as usize),
-    ) {
-        Ok(ctx) => {
-            *context = ctx;
-            VA_STATUS_SUCCESS
-        }
-        Err(e) => e,
-    }
+    guard(
+        "vaCreateContext",
+        |status| {
+            format!(
+                "config={config_id} {picture_width}x{picture_height} \
+                 num_render_targets={num_render_targets} context={}",
+                Out(status, context)
+            )
+        },
+        || {
+            let driver = &*((*ctx).pDriverData as *const Driver);
+
+            match app_slice(render_targets, num_render_targets).and_then(|render_targets| {
+                driver.create_context(
+                    config_id,
+                    picture_width,
+                    picture_height,
+                    flag,
+                    render_targets,
+                )
+            }) {
+                Ok(ctx) => {
+                    *context = ctx;
+                    VA_STATUS_SUCCESS
+                }
+                Err(e) => e,
+            }
+        },
+    )
 }
 
 unsafe extern "C" fn destroy_context(ctx: VADriverContextP, context: VAContextID) -> VAStatus {
-    let driver = &mut *((*ctx).pDriverData as *mut Driver);
-
-    match driver.contexts.get_mut(context as usize) {
-        Some(s) => {
-            *s = None;
-            VA_STATUS_SUCCESS
-        }
-        None => VA_STATUS_ERROR_INVALID_CONTEXT,
-    }
+    guard(
+        "vaDestroyContext",
+        |_| format!("context={context}"),
+        || {
+            let driver = &*((*ctx).pDriverData as *const Driver);
+
+            match driver.destroy_context(context) {
+                Ok(_) => VA_STATUS_SUCCESS,
+                Err(e) => e,
+            }
+        },
+    )
 }
 
 unsafe extern "C" fn create_buffer(
@@ -434,28 +1499,34 @@ unsafe extern "C" fn create_buffer(
     data: *mut c_void,
     buf_id: *mut VABufferID,
 ) -> VAStatus {
-    let driver = &mut *((*ctx).pDriverData as *mut Driver);
-
-    match driver.create_buffer(
-        context,
-        type_,
-        size,
-        num_elements,
-        if data.is_null() {
-            None
-        } else {
-            Some(slice::from_raw_parts(
-                data as _,
-                (size * num_elements) as usize,
-            ))
+    guard(
+        "vaCreateBuffer",
+        |status| {
+            format!(
+                "context={context} type={type_} size={size} num_elements={num_elements} buf={}",
+                Out(status, buf_id)
+            )
         },
-    ) {
-        Ok(buf) => {
-            *buf_id = buf;
-            VA_STATUS_SUCCESS
-        }
-        Err(e) => e,
-    }
+        || {
+            let driver = &*((*ctx).pDriverData as *const Driver);
+
+            let data = match size.checked_mul(num_elements) {
+                // no data is fine, the buffer starts out zeroed
+                Some(_) if data.is_null() => Ok(None),
+                Some(len) => app_slice(data as *const u8, len).map(Some),
+                None => Err(VA_STATUS_ERROR_INVALID_PARAMETER),
+            };
+            match data
+                .and_then(|data| driver.create_buffer(context, type_, size, num_elements, data))
+            {
+                Ok(buf) => {
+                    *buf_id = buf;
+                    VA_STATUS_SUCCESS
+                }
+                Err(e) => e,
+            }
+        },
+    )
 }
 
 unsafe extern "C" fn map_buffer(
@@ -463,34 +1534,48 @@ unsafe extern "C" fn map_buffer(
     buf_id: VABufferID,
     pbuf: *mut *mut c_void,
 ) -> VAStatus {
-    let driver = &mut *((*ctx).pDriverData as *mut Driver);
-
-    match driver.buffer_mut(buf_id) {
-        Ok(buf) => {
-            // NOTE: this is suuuuper sketchy and probably violates aliasing rules
-            // there's no way to enforce the aliasing rules though, unfortunately...altho I'm sure this could be improved
-            pbuf.write(buf.map_mut().as_mut_ptr() as _);
-            VA_STATUS_SUCCESS
-        }
-        Err(e) => e,
-    }
+    guard(
+        "vaMapBuffer",
+        |_| format!("buf={buf_id}"),
+        || {
+            let driver = &*((*ctx).pDriverData as *const Driver);
+
+            match driver.map_buffer(buf_id) {
+                Ok(map) => {
+                    pbuf.write(map as _);
+                    VA_STATUS_SUCCESS
+                }
+                Err(e) => e,
+            }
+        },
+    )
 }
 
 unsafe extern "C" fn unmap_buffer(ctx: VADriverContextP, buf_id: VABufferID) -> VAStatus {
-    let driver = &mut *((*ctx).pDriverData as *mut Driver);
-    // unmap is free in this implementation, it's just shared memory
-    VA_STATUS_SUCCESS
+    guard(
+        "vaUnmapBuffer",
+        |_| format!("buf={buf_id}"),
+        || {
+            let driver = &*((*ctx).pDriverData as *const Driver);
+            // unmap is free in this implementation, it's just shared memory
+            VA_STATUS_SUCCESS
+        },
+    )
 }
 
 unsafe extern "C" fn destroy_buffer(ctx: VADriverContextP, buffer_id: VABufferID) -> VAStatus {
-    let driver = &mut *((*ctx).pDriverData as *mut Driver);
-
-    if let Some(buf) = driver.buffers.get_mut(buffer_id as usize) {
-        *buf = None;
-        VA_STATUS_SUCCESS
-    } else {
-        VA_STATUS_ERROR_INVALID_BUFFER
-    }
+    guard(
+        "vaDestroyBuffer",
+        |_| format!("buf={buffer_id}"),
+        || {
+            let driver = &*((*ctx).pDriverData as *const Driver);
+
+            match driver.destroy_buffer(buffer_id) {
+                Ok(_) => VA_STATUS_SUCCESS,
+                Err(e) => e,
+            }
+        },
+    )
 }
 
 unsafe extern "C" fn begin_picture(
@@ -498,15 +1583,18 @@ unsafe extern "C" fn begin_picture(
     context: VAContextID,
     render_target: VASurfaceID,
 ) -> VAStatus {
-    let driver = &mut *((*ctx).pDriverData as *mut Driver);
-
-    match driver.context_mut(context) {
-        Ok(ctx) => {
-            ctx.render_target = Some(render_target);
-            VA_STATUS_SUCCESS
-        }
-        Err(e) => e,
-    }
+    guard(
+        "vaBeginPicture",
+        |_| format!("context={context} target={render_target}"),
+        || {
+            let driver = &*((*ctx).pDriverData as *const Driver);
+
+            match driver.begin_picture(context, render_target) {
+                Ok(_) => VA_STATUS_SUCCESS,
+                Err(e) => e,
+            }
+        },
+    )
 }
 
 unsafe extern "C" fn render_picture(
@@ -515,32 +1603,50 @@ unsafe extern "C" fn render_picture(
     buffers: *mut VABufferID,
     num_buffers: c_int,
 ) -> VAStatus {
-    let driver = &mut *((*ctx).pDriverData as *mut Driver);
-
-    match driver.render_picture(
-        context,
-        slice::from_raw_parts(buffers, num_buffers as usize),
-    ) {
-        Ok(_) => VA_STATUS_SUCCESS,
-        Err(e) => e,
-    }
+    guard(
+        "vaRenderPicture",
+        |_| format!("context={context} num_buffers={num_buffers}"),
+        || {
+            let driver = &*((*ctx).pDriverData as *const Driver);
+
+            match app_slice(buffers, num_buffers)
+                .and_then(|buffers| driver.render_picture(context, buffers))
+            {
+                Ok(_) => VA_STATUS_SUCCESS,
+                Err(e) => e,
+            }
+        },
+    )
 }
 
 unsafe extern "C" fn end_picture(ctx: VADriverContextP, context: VAContextID) -> VAStatus {
-    let driver = &mut *((*ctx).pDriverData as *mut Driver);
-
-    match driver.context_mut(context) {
-        Ok(ctx) => {
-            ctx.render_target = None;
-            VA_STATUS_SUCCESS
-        }
-        Err(e) => e,
-    }
+    guard(
+        "vaEndPicture",
+        |_| format!("context={context}"),
+        || {
+            let driver = &*((*ctx).pDriverData as *const Driver);
+
+            match driver.end_picture(context) {
+                Ok(_) => VA_STATUS_SUCCESS,
+                Err(e) => e,
+            }
+        },
+    )
 }
 
 unsafe extern "C" fn sync_surface(ctx: VADriverContextP, render_target: VASurfaceID) -> VAStatus {
-    // nothing to do, all CPU
-    VA_STATUS_SUCCESS
+    guard(
+        "vaSyncSurface",
+        |_| format!("surface={render_target}"),
+        || {
+            let driver = &*((*ctx).pDriverData as *const Driver);
+
+            match driver.sync_surface(render_target) {
+                Ok(_) => VA_STATUS_SUCCESS,
+                Err(e) => e,
+            }
+        },
+    )
 }
 
 unsafe extern "C" fn query_image_formats(
@@ -548,12 +1654,19 @@ unsafe extern "C" fn query_image_formats(
     format_list: *mut VAImageFormat,
     num_formats: *mut c_int,
 ) -> VAStatus {
-    let driver = &mut *((*ctx).pDriverData as *mut Driver);
+    guard(
+        "vaQueryImageFormats",
+        |status| format!("num_formats={}", Out(status, num_formats)),
+        || {
+            let driver = &*((*ctx).pDriverData as *const Driver);
 
-    *num_formats =
-        driver.query_image_formats(slice::from_raw_parts_mut(format_list, NUM_IMAGE_FORMATS));
+            let formats = driver.query_image_formats();
+            slice::from_raw_parts_mut(format_list, formats.len()).copy_from_slice(&formats);
+            *num_formats = formats.len() as c_int;
 
-    VA_STATUS_SUCCESS
+            VA_STATUS_SUCCESS
+        },
+    )
 }
 
 unsafe extern "C" fn derive_image(
@@ -561,27 +1674,36 @@ unsafe extern "C" fn derive_image(
     surface: VASurfaceID,
     image: *mut VAImage,
 ) -> VAStatus {
-    let driver = &mut *((*ctx).pDriverData as *mut Driver);
-
-    match driver.derive_image(surface) {
-        Ok(i) => {
-            *image = i;
-            VA_STATUS_SUCCESS
-        }
-        Err(e) => e,
-    }
+    guard(
+        "vaDeriveImage",
+        |_| format!("surface={surface}"),
+        || {
+            let driver = &*((*ctx).pDriverData as *const Driver);
+
+            match driver.derive_image(surface) {
+                Ok(i) => {
+                    *image = i;
+                    VA_STATUS_SUCCESS
+                }
+                Err(e) => e,
+            }
+        },
+    )
 }
 
 unsafe extern "C" fn destroy_image(ctx: VADriverContextP, image: VAImageID) -> VAStatus {
-    let driver = &mut *((*ctx).pDriverData as *mut Driver);
-
-    match driver.images.get_mut(image as usize) {
-        Some(s) => {
-            *s = None;
-            VA_STATUS_SUCCESS
-        }
-        None => VA_STATUS_ERROR_INVALID_CONTEXT,
-    }
+    guard(
+        "vaDestroyImage",
+        |_| format!("image={image}"),
+        || {
+            let driver = &*((*ctx).pDriverData as *const Driver);
+
+            match driver.destroy_image(image) {
+                Ok(_) => VA_STATUS_SUCCESS,
+                Err(e) => e,
+            }
+        },
+    )
 }
 
 unsafe extern "C" fn create_surfaces2(
@@ -594,17 +1716,27 @@ unsafe extern "C" fn create_surfaces2(
     attrib_list: *mut VASurfaceAttrib,
     num_attribs: c_uint,
 ) -> VAStatus {
-    let driver = &mut *((*ctx).pDriverData as *mut Driver);
-
-    driver.create_surfaces(
-        format,
-        width,
-        height,
-        slice::from_raw_parts_mut(surfaces, num_surfaces as usize),
-        slice::from_raw_parts(attrib_list, num_attribs as usize),
-    );
-
-    VA_STATUS_SUCCESS
+    guard(
+        "vaCreateSurfaces2",
+        |_| {
+            format!(
+                "format={format:#x} {width}x{height} num_surfaces={num_surfaces} \
+                 num_attribs={num_attribs}"
+            )
+        },
+        || {
+            let driver = &*((*ctx).pDriverData as *const Driver);
+
+            let args = app_slice_mut(surfaces, num_surfaces)
+                .and_then(|surfaces| Ok((surfaces, app_slice(attrib_list, num_attribs)?)));
+            match args.and_then(|(surfaces, attribs)| {
+                driver.create_surfaces(format, width, height, surfaces, attribs)
+            }) {
+                Ok(_) => VA_STATUS_SUCCESS,
+                Err(e) => e,
+            }
+        },
+    )
 }
 
 unsafe extern "C" fn query_surface_attributes(
@@ -613,18 +1745,30 @@ unsafe extern "C" fn query_surface_attributes(
     attrib_list: *mut VASurfaceAttrib,
     num_attribs: *mut c_uint,
 ) -> VAStatus {
-    let driver = &mut *((*dpy).pDriverData as *mut Driver);
-
-    match driver.query_surface_attributes(
-        config,
-        slice::from_raw_parts_mut(attrib_list, NUM_ATTRIBUTES),
-    ) {
-        Ok(num) => {
-            *num_attribs = num as c_uint;
+    guard(
+        "vaQuerySurfaceAttributes",
+        |status| format!("config={config} num_attribs={}", Out(status, num_attribs)),
+        || {
+            let driver = &*((*dpy).pDriverData as *const Driver);
+
+            let attribs = match driver.query_surface_attributes(config) {
+                Ok(attribs) => attribs,
+                Err(e) => return e,
+            };
+
+            // asked for the count first, then again with room for them all
+            let room = *num_attribs as usize;
+            *num_attribs = attribs.len() as c_uint;
+            if attrib_list.is_null() {
+                return VA_STATUS_SUCCESS;
+            }
+            if room < attribs.len() {
+                return VA_STATUS_ERROR_MAX_NUM_EXCEEDED;
+            }
+            slice::from_raw_parts_mut(attrib_list, attribs.len()).copy_from_slice(&attribs);
             VA_STATUS_SUCCESS
-        }
-        Err(e) => e,
-    }
+        },
+    )
 }
 
 unsafe extern "C" fn acquire_buffer_handle(
@@ -632,20 +1776,32 @@ unsafe extern "C" fn acquire_buffer_handle(
     buf_id: VABufferID,
     buf_info: *mut VABufferInfo,
 ) -> VAStatus {
-    let driver = &mut *((*ctx).pDriverData as *mut Driver);
-
-    match driver.acquire_buffer_handle(buf_id, (*buf_info).mem_type) {
-        Ok(info) => {
-            *buf_info = info;
-            VA_STATUS_SUCCESS
-        }
-        Err(e) => e,
-    }
+    guard(
+        "vaAcquireBufferHandle",
+        |_| format!("buf={buf_id}"),
+        || {
+            let driver = &*((*ctx).pDriverData as *const Driver);
+
+            match driver.acquire_buffer_handle(buf_id, (*buf_info).mem_type) {
+                Ok(info) => {
+                    *buf_info = info;
+                    VA_STATUS_SUCCESS
+                }
+                Err(e) => e,
+            }
+        },
+    )
 }
 
 unsafe extern "C" fn release_buffer_handle(ctx: VADriverContextP, buf_id: VABufferID) -> VAStatus {
-    // nothing to do (yet at least)
-    VA_STATUS_SUCCESS
+    guard(
+        "vaReleaseBufferHandle",
+        |_| format!("buf={buf_id}"),
+        || {
+            // nothing to do (yet at least)
+            VA_STATUS_SUCCESS
+        },
+    )
 }
 
 unsafe extern "C" fn export_surface_handle(
@@ -655,19 +1811,26 @@ unsafe extern "C" fn export_surface_handle(
     flags: u32,
     descriptor: *mut c_void,
 ) -> VAStatus {
-    let driver = &mut *((*ctx).pDriverData as *mut Driver);
-
-    match mem_type {
-        VA_SURFACE_ATTRIB_MEM_TYPE_DRM_PRIME_2 => match driver.export_surface_handle_drm_prime(
-            surface_id,
-            flags,
-            &mut *(descriptor as *mut VADRMPRIMESurfaceDescriptor),
-        ) {
-            Ok(_) => VA_STATUS_SUCCESS,
-            Err(e) => e,
+    guard(
+        "vaExportSurfaceHandle",
+        |_| format!("surface={surface_id} mem_type={mem_type:#x} flags={flags:#x}"),
+        || {
+            let driver = &*((*ctx).pDriverData as *const Driver);
+
+            match mem_type {
+                VA_SURFACE_ATTRIB_MEM_TYPE_DRM_PRIME_2 => match driver
+                    .export_surface_handle_drm_prime(
+                        surface_id,
+                        flags,
+                        &mut *(descriptor as *mut VADRMPRIMESurfaceDescriptor),
+                    ) {
+                    Ok(_) => VA_STATUS_SUCCESS,
+                    Err(e) => e,
+                },
+                _ => VA_STATUS_ERROR_UNSUPPORTED_MEMORY_TYPE,
+            }
         },
-        _ => todo!(),
-    }
+    )
 }
 
 unsafe extern "C" fn sync_buffer(
@@ -675,10 +1838,18 @@ unsafe extern "C" fn sync_buffer(
     buf_id: VABufferID,
     timeout_ns: u64,
 ) -> VAStatus {
-    let driver = &mut *((*ctx).pDriverData as *mut Driver);
-
-    driver.sync_buffer(buf_id, timeout_ns);
-    VA_STATUS_SUCCESS
+    guard(
+        "vaSyncBuffer",
+        |_| format!("buf={buf_id} timeout_ns={timeout_ns}"),
+        || {
+            let driver = &*((*ctx).pDriverData as *const Driver);
+
+            match driver.sync_buffer(buf_id) {
+                Ok(_) => VA_STATUS_SUCCESS,
+                Err(e) => e,
+            }
+        },
+    )
 }
 
 unsafe extern "C" fn vpp_query_video_proc_filter_caps(
@@ -688,10 +1859,16 @@ unsafe extern "C" fn vpp_query_video_proc_filter_caps(
     filter_caps: *mut c_void,
     num_filter_caps: *mut c_uint,
 ) -> VAStatus {
-    let driver = &mut *((*ctx).pDriverData as *mut Driver);
-
-    // driver.vpp_query_video_proc_filter_caps()
-    todo!()
+    guard(
+        "vaQueryVideoProcFilterCaps",
+        |_| format!("context={context} type={type_}"),
+        || {
+            let driver = &*((*ctx).pDriverData as *const Driver);
+
+            // driver.vpp_query_video_proc_filter_caps()
+            VA_STATUS_ERROR_UNIMPLEMENTED
+        },
+    )
 }
 
 unsafe extern "C" fn vpp_query_video_proc_pipeline_caps(
@@ -701,17 +1878,28 @@ unsafe extern "C" fn vpp_query_video_proc_pipeline_caps(
     num_filters: c_uint,
     pipeline_caps: *mut VAProcPipelineCaps,
 ) -> VAStatus {
-    let driver = &mut *((*ctx).pDriverData as *mut Driver);
-
-    assert_eq!(num_filters, 0); // todo
-
-    driver.vpp_query_video_proc_pipeline_cpas(&mut *pipeline_caps);
+    guard(
+        "vaQueryVideoProcPipelineCaps",
+        |_| format!("context={context} num_filters={num_filters}"),
+        || {
+            let driver = &*((*ctx).pDriverData as *const Driver);
+
+            if num_filters != 0 {
+                // no filters yet
+                return VA_STATUS_ERROR_UNIMPLEMENTED;
+            }
 
-    VA_STATUS_SUCCESS
+            match driver.vpp_query_video_proc_pipeline_cpas(&mut *pipeline_caps) {
+                Ok(_) => VA_STATUS_SUCCESS,
+                Err(e) => e,
+            }
+        },
+    )
 }
 
 unsafe extern "C" fn unimpl() -> VAStatus {
-    todo!()
+    debug!("unimplemented call");
+    VA_STATUS_ERROR_UNIMPLEMENTED
 }
 
 fn align_up(p: usize, align: usize) -> usize {
@@ -732,24 +1920,30 @@ struct UDmabufAllocation {
 }
 
 impl UDmaBuf {
-    fn new() -> Self {
-        Self {
-            fd: File::open("/dev/udmabuf").unwrap().into(),
-        }
+    fn new() -> Result<Self, VAStatus> {
+        Ok(Self {
+            fd: File::open("/dev/udmabuf")
+                .map_err(|_| VA_STATUS_ERROR_OPERATION_FAILED)?
+                .into(),
+        })
     }
 
-    fn alloc_dmabuf(&mut self, size: usize) -> UDmabufAllocation {
+    fn alloc_dmabuf(&self, size: usize) -> Result<UDmabufAllocation, VAStatus> {
         unsafe {
             let memfd = MemfdOptions::default()
                 .allow_sealing(true)
                 .create("memfd")
-                .unwrap();
+                .map_err(|_| VA_STATUS_ERROR_ALLOCATION_FAILED)?;
 
             let size_aligned = align_up(size, page_size::get());
 
             let res = ftruncate(memfd.as_raw_fd(), size_aligned as i64);
-            assert_eq!(res, 0);
-            memfd.add_seal(FileSeal::SealShrink).unwrap();
+            if res != 0 {
+                return Err(VA_STATUS_ERROR_ALLOCATION_FAILED);
+            }
+            memfd
+                .add_seal(FileSeal::SealShrink)
+                .map_err(|_| VA_STATUS_ERROR_ALLOCATION_FAILED)?;
 
             let dmabuf_fd = udmabuf_create(
                 self.fd.as_raw_fd(),
@@ -760,44 +1954,61 @@ impl UDmaBuf {
                     size: size_aligned as u64,
                 },
             )
-            .unwrap();
+            .map_err(|_| VA_STATUS_ERROR_ALLOCATION_FAILED)?;
 
-            UDmabufAllocation {
+            Ok(UDmabufAllocation {
                 dmabuf: OwnedFd::from_raw_fd(dmabuf_fd),
                 memfd,
-            }
+            })
         }
     }
 }
 
+// the application keeps its own fd
+fn dup_fd(fd: RawFd) -> Result<OwnedFd, VAStatus> {
+    unsafe { BorrowedFd::borrow_raw(fd) }
+        .try_clone_to_owned()
+        .map_err(|_| VA_STATUS_ERROR_INVALID_PARAMETER)
+}
+
 ioctl_write_ptr!(udmabuf_create, b'u', 0x42, udmabuf_create);
 
 impl Driver {
-    unsafe fn init_context(ctx: &mut VADriverContext) {
+    fn new(config: DriverConfig, trace: Option<Mutex<Recorder>>) -> Result<Self, VAStatus> {
         dcp::initialize();
 
-        ctx.pDriverData = Box::into_raw(Box::new(Driver {
+        Ok(Driver {
             // egl,
             // gles,
             // egl_display,
             // egl_ctx,
             // gbm,
-            udma: UDmaBuf::new(),
-            surfaces: Default::default(),
-            configs: Default::default(),
-            contexts: Default::default(),
-            images: Default::default(),
-            buffers: Default::default(),
+            udma: UDmaBuf::new()?,
+            config,
+            surfaces: Table::new(VA_STATUS_ERROR_INVALID_SURFACE),
+            configs: Table::new(VA_STATUS_ERROR_INVALID_CONFIG),
+            contexts: Table::new(VA_STATUS_ERROR_INVALID_CONTEXT),
+            images: Table::new(VA_STATUS_ERROR_INVALID_IMAGE),
+            buffers: Table::new(VA_STATUS_ERROR_INVALID_BUFFER),
+            trace,
             // egl_export_dmabuf_image_mesa,
-        })) as *mut c_void;
+        })
+    }
+
+    unsafe fn init_context(ctx: &mut VADriverContext) -> Result<(), VAStatus> {
+        let config = DriverConfig::load();
+        info!("{config:?}");
+        let trace = Recorder::from_env(&config);
+
+        ctx.pDriverData = Box::into_raw(Box::new(Driver::new(config, trace)?)) as *mut c_void;
 
         ctx.version_major = VA_MAJOR_VERSION as i32;
         ctx.version_minor = VA_MINOR_VERSION as i32;
 
-        ctx.max_profiles = NUM_PROFILES as i32;
-        ctx.max_entrypoints = NUM_ENTRYPOINTS as i32;
+        ctx.max_profiles = caps::profiles().len() as i32;
+        ctx.max_entrypoints = caps::max_entrypoints() as i32;
         ctx.max_attributes = NUM_ATTRIBUTES as i32;
-        ctx.max_image_formats = NUM_IMAGE_FORMATS as i32;
+        ctx.max_image_formats = caps::fourccs().len() as i32;
         ctx.max_subpic_formats = NUM_SUBPIC_FORMATS as i32;
         ctx.max_display_attributes = NUM_DISPLAY_ATTRIBUTES as i32;
         ctx.str_vendor = c_str!("libva-x264").as_ptr();
@@ -871,173 +2082,457 @@ impl Driver {
         let vtable_vpp = &mut *ctx.vtable_vpp;
         // vtable_vpp.vaQueryVideoProcFilterCaps = Some(vpp_query_video_proc_filter_caps);
         vtable_vpp.vaQueryVideoProcPipelineCaps = Some(vpp_query_video_proc_pipeline_caps);
-    }
 
-    const IMAGE_FMT_NV12: VAImageFormat = VAImageFormat {
-        fourcc: VA_FOURCC_NV12,
-        byte_order: VA_LSB_FIRST,
-        bits_per_pixel: 12,
-        depth: 0,
-        red_mask: 0,
-        green_mask: 0,
-        blue_mask: 0,
-        alpha_mask: 0,
-        va_reserved: [0; 4],
-    };
-    const IMAGE_FMT_YUV420: VAImageFormat = VAImageFormat {
-        fourcc: VA_FOURCC_I420,
-        byte_order: VA_LSB_FIRST,
-        bits_per_pixel: 12,
-        depth: 0,
-        red_mask: 0,
-        green_mask: 0,
-        blue_mask: 0,
-        alpha_mask: 0,
-        va_reserved: [0; 4],
-    };
-    const IMAGE_FMT_BGRX: VAImageFormat = VAImageFormat {
-        fourcc: VA_FOURCC_BGRX,
-        byte_order: VA_LSB_FIRST,
-        bits_per_pixel: 32,
-        depth: 0,
-        red_mask: 0xff000000,
-        green_mask: 0x00ff0000,
-        blue_mask: 0x0000ff00,
-        alpha_mask: 0x000000ff,
-        va_reserved: [0; 4],
-    };
+        Ok(())
+    }
 
-    fn query_image_formats(&self, num_image_formats: &mut [VAImageFormat]) -> i32 {
-        num_image_formats[0] = Driver::IMAGE_FMT_NV12;
-        1
+    // every format a surface can be in, which is what vaDeriveImage gives out
+    fn query_image_formats(&self) -> Vec<VAImageFormat> {
+        caps::fourccs()
+            .into_iter()
+            .filter_map(format::layout)
+            .map(Layout::image_format)
+            .collect()
     }
 
     fn create_surfaces(
-        &mut self,
+        &self,
         format: u32,
         width: u32,
         height: u32,
         surfaces: &mut [u32],
         attribs: &[VASurfaceAttrib],
-    ) {
+    ) -> Result<(), VAStatus> {
+        let mut trace = self.trace();
+
+        let (max_width, max_height) = caps::max_size();
+        if width == 0 || height == 0 || width > max_width || height > max_height {
+            return Err(VA_STATUS_ERROR_RESOLUTION_NOT_SUPPORTED);
+        }
+
         let mut fourcc = None;
+        let mut mem_type = VA_SURFACE_ATTRIB_MEM_TYPE_VA;
+        let mut descriptor: *const c_void = null_mut();
         for attrib in attribs {
             match attrib.type_ {
                 VASurfaceAttribType_VASurfaceAttribPixelFormat => {
                     fourcc = Some(unsafe { attrib.value.value.i } as u32);
                 }
                 VASurfaceAttribType_VASurfaceAttribMemoryType => {
-                    let v = unsafe { attrib.value.value.i };
-                    assert_eq!(v, VA_SURFACE_ATTRIB_MEM_TYPE_VA as i32);
+                    mem_type = unsafe { attrib.value.value.i } as u32;
+                    if mem_type.count_ones() != 1 || mem_type & !caps::MEMORY_TYPES != 0 {
+                        return Err(VA_STATUS_ERROR_UNSUPPORTED_MEMORY_TYPE);
+                    }
+                }
+                VASurfaceAttribType_VASurfaceAttribExternalBufferDescriptor => {
+                    descriptor = unsafe { attrib.value.value.p };
+                }
+                // only a hint, everything is plain memory here
+                VASurfaceAttribType_VASurfaceAttribUsageHint => {}
+                _ => return Err(VA_STATUS_ERROR_ATTR_NOT_SUPPORTED),
+            }
+        }
+
+        if !format::LAYOUTS
+            .iter()
+            .any(|layout| layout.rt_format == format)
+        {
+            return Err(VA_STATUS_ERROR_UNSUPPORTED_RT_FORMAT);
+        }
+        let fourcc = fourcc.or(default_fourcc(format));
+
+        // how each imported surface is laid out, for the trace to replay it the same way
+        let mut imported = Vec::new();
+        for (i, s) in surfaces.iter_mut().enumerate() {
+            if mem_type != VA_SURFACE_ATTRIB_MEM_TYPE_VA {
+                let surface =
+                    unsafe { self.import_surface(mem_type, descriptor, i, format, width, height)? };
+                if trace.is_some() {
+                    let Buffer::Surface { size, .. } = *lock(&self.buffer(surface.buffer_id)?)
+                    else {
+                        unreachable!()
+                    };
+                    imported.push((surface.format.fourcc, surface.planes.clone(), size));
+                }
+                *s = self.surfaces.insert(surface)?;
+                continue;
+            }
+
+            let layout = fourcc
+                .and_then(format::layout)
+                .filter(|layout| layout.rt_format == format)
+                .ok_or(VA_STATUS_ERROR_INVALID_IMAGE_FORMAT)?;
+            // RGB rows line up to 512 bytes, YUV ones to 2048
+            let align = if layout.yuv.is_empty() { 512 } else { 2048 };
+            let (planes, size) = layout.allocate(width, height, align);
+            *s = self.alloc_surface(width, height, layout, planes, size)?;
+        }
+
+        if let Some(trace) = &mut trace {
+            if mem_type == VA_SURFACE_ATTRIB_MEM_TYPE_VA {
+                trace.record(Record::CreateSurfaces {
+                    format,
+                    width,
+                    height,
+                    fourcc: fourcc.unwrap_or(0),
+                    ids: surfaces.to_vec(),
+                });
+            }
+            for (&id, (fourcc, planes, size)) in surfaces.iter().zip(imported) {
+                trace.record(Record::ImportSurface {
+                    mem_type,
+                    width,
+                    height,
+                    fourcc,
+                    planes,
+                    size: size as u32,
+                    id,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // a surface in memory of our own, `size` bytes with the planes where `planes` says
+    fn alloc_surface(
+        &self,
+        width: u32,
+        height: u32,
+        layout: &Layout,
+        planes: Vec<PlaneInfo>,
+        size: usize,
+    ) -> Result<VASurfaceID, VAStatus> {
+        let buf = self.udma.alloc_dmabuf(size)?;
+        let buffer_id = self
+            .buffers
+            .insert(Buffer::from_surface(SurfaceMemory::Udmabuf(buf), size)?)?;
+
+        self.surfaces.insert(Surface {
+            width,
+            height,
+            format: layout.image_format(),
+            buffer_id,
+            planes,
+            color_standard: _VAProcColorStandardType_VAProcColorStandardNone,
+            full_range: false,
+        })
+    }
+
+    fn create_config(
+        &self,
+        profile: i32,
+        entrypoint: u32,
+        attribs: &[VAConfigAttrib],
+    ) -> Result<u32, VAStatus> {
+        let mut trace = self.trace();
+
+        let cap = caps::find(profile, entrypoint)?;
+        let mut supported = CONFIG_ATTRIBUTES.map(|type_| VAConfigAttrib { type_, value: 0 });
+        self.get_config_attributes(profile, entrypoint, &mut supported)?;
+
+        // unless the application asks for something else
+        let mut rt_format = VA_RT_FORMAT_YUV420;
+        let mut rc_mode = match cap.encode {
+            Some(_) => VA_RC_CBR,
+            None => 0,
+        };
+
+        for attrib in attribs {
+            let allowed = supported
+                .iter()
+                .find(|s| s.type_ == attrib.type_ && s.value != VA_ATTRIB_NOT_SUPPORTED as u32)
+                .ok_or(VA_STATUS_ERROR_ATTR_NOT_SUPPORTED)?
+                .value;
+
+            match attrib.type_ {
+                // bitmasks of what we can do, out of which the application picks one
+                VAConfigAttribType_VAConfigAttribRTFormat
+                | VAConfigAttribType_VAConfigAttribRateControl => {
+                    if attrib.value.count_ones() != 1 || attrib.value & allowed == 0 {
+                        return Err(VA_STATUS_ERROR_INVALID_VALUE);
+                    }
+                    if attrib.type_ == VAConfigAttribType_VAConfigAttribRTFormat {
+                        rt_format = attrib.value;
+                    } else {
+                        rc_mode = attrib.value;
+                    }
+                }
+                // fixed, at most what's reported
+                _ => {
+                    if attrib.value > allowed {
+                        return Err(VA_STATUS_ERROR_INVALID_VALUE);
+                    }
+                }
+            }
+        }
+
+        let id = self.configs.insert(Config {
+            profile,
+            entrypoint,
+            attribs: attribs.to_owned(),
+            rt_format,
+            rc_mode,
+        })?;
+
+        if let Some(trace) = &mut trace {
+            trace.record(Record::CreateConfig {
+                profile,
+                entrypoint,
+                attribs: attribs.iter().map(|a| (a.type_, a.value)).collect(),
+                id,
+            });
+        }
+        Ok(id)
+    }
+
+    fn destroy_config(&self, config_id: u32) -> Result<(), VAStatus> {
+        let mut trace = self.trace();
+
+        self.configs.remove(config_id)?;
+
+        if let Some(trace) = &mut trace {
+            trace.record(Record::DestroyConfig { id: config_id });
+        }
+        Ok(())
+    }
+
+    // what the config ended up with: the defaults, with whatever the application asked for on top
+    fn query_config_attributes(
+        &self,
+        config_id: u32,
+    ) -> Result<(VAProfile, VAEntrypoint, Vec<VAConfigAttrib>), VAStatus> {
+        let config = self.config(config_id)?;
+        let config = lock(&config);
+
+        let mut attribs = CONFIG_ATTRIBUTES.map(|type_| VAConfigAttrib { type_, value: 0 });
+        self.get_config_attributes(config.profile, config.entrypoint, &mut attribs)?;
+        for attrib in &mut attribs {
+            // get_config_attributes has everything supported, the config has one of each
+            match attrib.type_ {
+                VAConfigAttribType_VAConfigAttribRTFormat => attrib.value = config.rt_format,
+                VAConfigAttribType_VAConfigAttribRateControl if config.rc_mode != 0 => {
+                    attrib.value = config.rc_mode;
+                }
+                _ => {
+                    if let Some(requested) = config.attribs.iter().find(|a| a.type_ == attrib.type_)
+                    {
+                        attrib.value = requested.value;
+                    }
+                }
+            }
+        }
+
+        Ok((
+            config.profile,
+            config.entrypoint,
+            attribs
+                .into_iter()
+                .filter(|a| a.value != VA_ATTRIB_NOT_SUPPORTED as u32)
+                .collect(),
+        ))
+    }
+
+    fn query_surface_attributes(&self, config: u32) -> Result<Vec<VASurfaceAttrib>, VAStatus> {
+        let config = self.config(config)?;
+        let config = lock(&config);
+        let cap = caps::find(config.profile, config.entrypoint)?;
+
+        let attrib = |type_, flags, value: Option<i32>| {
+            let mut attrib = VASurfaceAttrib::default();
+            attrib.type_ = type_;
+            attrib.flags = flags;
+            match value {
+                Some(i) => {
+                    attrib.value.type_ = VAGenericValueType_VAGenericValueTypeInteger;
+                    attrib.value.value.i = i;
+                }
+                None => {
+                    attrib.value.type_ = VAGenericValueType_VAGenericValueTypePointer;
+                    attrib.value.value.p = null_mut();
+                }
+            }
+            attrib
+        };
+        let settable = VA_SURFACE_ATTRIB_GETTABLE | VA_SURFACE_ATTRIB_SETTABLE;
+
+        let mut attribs: Vec<_> = cap
+            .input_fourccs
+            .iter()
+            .chain(cap.output_fourccs)
+            .map(|fourcc| {
+                attrib(
+                    VASurfaceAttribType_VASurfaceAttribPixelFormat,
+                    settable,
+                    Some(*fourcc as i32),
+                )
+            })
+            .collect();
+        attribs.extend([
+            attrib(
+                VASurfaceAttribType_VASurfaceAttribMemoryType,
+                settable,
+                Some(caps::MEMORY_TYPES as i32),
+            ),
+            attrib(
+                VASurfaceAttribType_VASurfaceAttribExternalBufferDescriptor,
+                VA_SURFACE_ATTRIB_SETTABLE,
+                None,
+            ),
+            attrib(
+                VASurfaceAttribType_VASurfaceAttribMinWidth,
+                VA_SURFACE_ATTRIB_GETTABLE,
+                Some(1),
+            ),
+            attrib(
+                VASurfaceAttribType_VASurfaceAttribMinHeight,
+                VA_SURFACE_ATTRIB_GETTABLE,
+                Some(1),
+            ),
+            attrib(
+                VASurfaceAttribType_VASurfaceAttribMaxWidth,
+                VA_SURFACE_ATTRIB_GETTABLE,
+                Some(cap.max_width as i32),
+            ),
+            attrib(
+                VASurfaceAttribType_VASurfaceAttribMaxHeight,
+                VA_SURFACE_ATTRIB_GETTABLE,
+                Some(cap.max_height as i32),
+            ),
+        ]);
+        Ok(attribs)
+    }
+
+    // a surface over memory the application already has, as the descriptor passed with the
+    // VASurfaceAttribExternalBufferDescriptor attribute lays it out
+    unsafe fn import_surface(
+        &self,
+        mem_type: u32,
+        descriptor: *const c_void,
+        index: usize,
+        format: u32,
+        width: u32,
+        height: u32,
+    ) -> Result<Surface, VAStatus> {
+        if descriptor.is_null() {
+            return Err(VA_STATUS_ERROR_INVALID_PARAMETER);
+        }
+
+        let (fourcc, planes, buffer) = match mem_type {
+            VA_SURFACE_ATTRIB_MEM_TYPE_DRM_PRIME_2 => {
+                let desc = &*(descriptor as *const VADRMPRIMESurfaceDescriptor);
+                // one descriptor is one surface, and all of it has to be in one buffer
+                if index != 0 || desc.num_objects != 1 {
+                    return Err(VA_STATUS_ERROR_INVALID_PARAMETER);
                 }
-                _ => todo!(),
-            }
-        }
-
-        for s in surfaces {
-            *s = self.surfaces.len() as u32;
-            self.surfaces.push(Some(match format {
-                VA_RT_FORMAT_RGB32 => match fourcc.unwrap() {
-                    VA_FOURCC_BGRX => {
-                        let stride = align_up(width as usize * 4, 512);
-                        let size = stride as i64 * height as i64;
-
-                        let buf = self.udma.alloc_dmabuf(size as usize);
-
-                        let buffer_id = self.buffers.len() as u32;
-                        self.buffers
-                            .push(Some(Buffer::from_surface(buf, size as usize)));
-                        Surface {
-                            width,
-                            height,
-                            format: Driver::IMAGE_FMT_BGRX,
-                            buffer_id,
-                            planes: vec![PlaneInfo {
-                                pitch: stride,
-                                offset: 0,
-                            }],
+
+                let mut planes = Vec::new();
+                let layers = desc
+                    .layers
+                    .get(..desc.num_layers as usize)
+                    .ok_or(VA_STATUS_ERROR_INVALID_PARAMETER)?;
+                for layer in layers {
+                    for p in 0..layer.num_planes as usize {
+                        if p >= layer.pitch.len() || layer.object_index[p] != 0 {
+                            return Err(VA_STATUS_ERROR_INVALID_PARAMETER);
                         }
+                        planes.push(PlaneInfo {
+                            pitch: layer.pitch[p] as usize,
+                            offset: layer.offset[p] as usize,
+                        });
                     }
-                    _ => todo!(),
-                },
-                VA_RT_FORMAT_YUV420 => {
-                    match fourcc.unwrap() {
-                        VA_FOURCC_NV12 => {
-                            let stride = align_up(width as usize, 2048);
-                            let size = stride as i64 * (height + (height + 1) / 2) as i64;
-
-                            let buf = self.udma.alloc_dmabuf(size as usize);
-
-                            let buffer_id = self.buffers.len() as u32;
-                            self.buffers
-                                .push(Some(Buffer::from_surface(buf, size as usize)));
-
-                            Surface {
-                                // width,
-                                // height,
-                                format: Driver::IMAGE_FMT_NV12,
-                                buffer_id,
-                                width,
-                                height,
-                                planes: vec![
-                                    PlaneInfo {
-                                        pitch: stride,
-                                        offset: 0,
-                                    },
-                                    PlaneInfo {
-                                        pitch: stride,
-                                        offset: stride * height as usize,
-                                    },
-                                ],
-                            }
+                }
+
+                // surfaces are read row by row, so nothing tiled (0 is DRM_FORMAT_MOD_LINEAR)
+                let object = &desc.objects[0];
+                if object.drm_format_modifier != 0 {
+                    return Err(VA_STATUS_ERROR_INVALID_PARAMETER);
+                }
+                (
+                    desc.fourcc,
+                    planes,
+                    Buffer::from_surface(
+                        SurfaceMemory::Dmabuf(dup_fd(object.fd)?),
+                        object.size as usize,
+                    )?,
+                )
+            }
+            VA_SURFACE_ATTRIB_MEM_TYPE_DRM_PRIME | VA_SURFACE_ATTRIB_MEM_TYPE_USER_PTR => {
+                let ext = &*(descriptor as *const VASurfaceAttribExternalBuffers);
+                // one buffer per surface
+                if ext.buffers.is_null()
+                    || index >= ext.num_buffers as usize
+                    || ext.num_planes as usize > ext.pitches.len()
+                {
+                    return Err(VA_STATUS_ERROR_INVALID_PARAMETER);
+                }
+
+                let handle = *ext.buffers.add(index);
+                let planes = (0..ext.num_planes as usize)
+                    .map(|p| PlaneInfo {
+                        pitch: ext.pitches[p] as usize,
+                        offset: ext.offsets[p] as usize,
+                    })
+                    .collect();
+                let size = ext.data_size as usize;
+                (
+                    ext.pixel_format,
+                    planes,
+                    match mem_type {
+                        VA_SURFACE_ATTRIB_MEM_TYPE_USER_PTR => {
+                            Buffer::from_user_ptr(handle as *mut u8, size)?
                         }
-                        _ => todo!(),
-                    }
-                }
-                _ => todo!(),
-            }))
+                        _ => Buffer::from_surface(
+                            SurfaceMemory::Dmabuf(dup_fd(handle as RawFd)?),
+                            size,
+                        )?,
+                    },
+                )
+            }
+            _ => return Err(VA_STATUS_ERROR_UNSUPPORTED_MEMORY_TYPE),
+        };
+
+        let layout = format::layout(fourcc).ok_or(VA_STATUS_ERROR_INVALID_IMAGE_FORMAT)?;
+        if layout.rt_format != format {
+            return Err(VA_STATUS_ERROR_INVALID_IMAGE_FORMAT);
         }
-    }
 
-    fn create_config(
-        &mut self,
-        profile: i32,
-        entrypoint: u32,
-        attribs: &[VAConfigAttrib],
-    ) -> Result<u32, VAStatus> {
-        self.configs.push(Some(Config {
-            profile,
-            entrypoint,
-            attribs: attribs.to_owned(),
-        }));
+        // everything reading surfaces takes the first plane from the start of the buffer, and
+        // the rest after it in order
+        let Buffer::Surface { size, .. } = &buffer else {
+            unreachable!()
+        };
+        let needed = layout.min_sizes(width, height);
+        let fits = planes.len() == needed.len()
+            && planes.first().is_some_and(|p| p.offset == 0)
+            && planes.windows(2).all(|p| p[0].offset < p[1].offset)
+            && planes.iter().zip(&needed).all(|(plane, &(row, rows))| {
+                plane.pitch >= row && plane.offset + plane.pitch * (rows - 1) + row <= *size
+            });
+        if !fits {
+            return Err(VA_STATUS_ERROR_INVALID_PARAMETER);
+        }
 
-        Ok((self.configs.len() - 1) as u32)
+        Ok(Surface {
+            width,
+            height,
+            format: layout.image_format(),
+            buffer_id: self.buffers.insert(buffer)?,
+            planes,
+            color_standard: _VAProcColorStandardType_VAProcColorStandardNone,
+            full_range: false,
+        })
     }
 
-    fn query_surface_attributes(
-        &mut self,
-        config: u32,
-        num_attributes: &mut [VASurfaceAttrib],
-    ) -> Result<usize, VAStatus> {
-        let c = self
-            .configs
-            .get_mut(config as usize)
-            .ok_or(VA_STATUS_ERROR_INVALID_CONFIG)?;
-
-        Ok(0)
-    }
-
-    fn derive_image(&mut self, surfaceid: u32) -> Result<VAImage, i32> {
-        let surface = self
-            .surfaces
-            .get(surfaceid as usize)
-            .ok_or(VA_STATUS_ERROR_INVALID_SURFACE)?
-            .as_ref()
-            .ok_or(VA_STATUS_ERROR_INVALID_SURFACE)?;
+    fn derive_image(&self, surfaceid: u32) -> Result<VAImage, i32> {
+        let mut trace = self.trace();
+
+        let surface = self.surfaces.get(surfaceid)?;
+        let surface = lock(&surface);
 
         // let buffer = &self.buffer(surface.buffer_id)?.buffer;
         let buffer = self.buffer(surface.buffer_id)?;
-        let (dmabuf_fd, size) = match buffer {
+        let buffer = lock(&buffer);
+        let (dmabuf_fd, size) = match &*buffer {
             Buffer::Surface { buf, size, .. } => (buf, size),
             _ => return Err(VA_STATUS_ERROR_INVALID_SURFACE),
         };
@@ -1057,13 +2552,27 @@ impl Driver {
         // let data_size =
         //     buffer.width().unwrap() * buffer.width().unwrap() * surface.format.bits_per_pixel / 8; // eeeeh this is not ideal--strides!!
         // let num_planes = buffer.plane_count().unwrap();
-        let width = surface.width.try_into().unwrap();
-        let height = surface.height.try_into().unwrap();
-        let data_size = (*size).try_into().unwrap();
+        let width = surface
+            .width
+            .try_into()
+            .map_err(|_| VA_STATUS_ERROR_INVALID_SURFACE)?;
+        let height = surface
+            .height
+            .try_into()
+            .map_err(|_| VA_STATUS_ERROR_INVALID_SURFACE)?;
+        let data_size = (*size)
+            .try_into()
+            .map_err(|_| VA_STATUS_ERROR_INVALID_SURFACE)?;
         let num_planes = surface.planes.len() as u32;
 
-        let image_id = self.images.len() as u32;
-        self.images.push(Some(Image {}));
+        let image_id = self.images.insert(Image {})?;
+
+        if let Some(trace) = &mut trace {
+            trace.record(Record::DeriveImage {
+                surface: surfaceid,
+                id: image_id,
+            });
+        }
 
         Ok(VAImage {
             image_id,
@@ -1083,82 +2592,132 @@ impl Driver {
     }
 
     fn create_context(
-        &mut self,
+        &self,
         config_id: u32,
         picture_width: i32,
         picture_height: i32,
         flag: i32,
         render_targets: &[u32],
     ) -> Result<u32, VAStatus> {
+        let mut trace = self.trace();
+
         let config = self.config(config_id)?;
-        self.contexts.push(Some(Context {
+        let config = lock(&config);
+        caps::find(config.profile, config.entrypoint)?.check_size(
+            picture_width.try_into().unwrap_or(0),
+            picture_height.try_into().unwrap_or(0),
+        )?;
+        for target in render_targets {
+            if lock(&self.surface(*target)?).rt_format() & config.rt_format == 0 {
+                return Err(VA_STATUS_ERROR_INVALID_SURFACE);
+            }
+        }
+
+        let id = self.contexts.insert(Context {
             render_target: None,
             config_id,
             picture_width,
             picture_height,
             flag,
             data: match config.entrypoint {
-                VAEntrypoint_VAEntrypointEncPicture => ContextData::Enc(Default::default()),
+                VAEntrypoint_VAEntrypointEncPicture => ContextData::Enc(EncData {
+                    rc: RateControl {
+                        mode: config.rc_mode,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                }),
                 VAEntrypoint_VAEntrypointVideoProc => ContextData::Proc,
-                _ => todo!(),
+                _ => return Err(VA_STATUS_ERROR_UNSUPPORTED_ENTRYPOINT),
             },
-        }));
-        Ok(self.contexts.len() as u32 - 1)
+        })?;
+
+        if let Some(trace) = &mut trace {
+            trace.record(Record::CreateContext {
+                config: config_id,
+                width: picture_width,
+                height: picture_height,
+                flag,
+                render_targets: render_targets.to_vec(),
+                id,
+            });
+        }
+        Ok(id)
+    }
+
+    fn destroy_context(&self, context: u32) -> Result<(), VAStatus> {
+        let mut trace = self.trace();
+
+        self.contexts.remove(context)?;
+
+        if let Some(trace) = &mut trace {
+            trace.record(Record::DestroyContext { id: context });
+        }
+        Ok(())
     }
 
-    fn destroy_surfaces(&mut self, surfaces: &[u32]) -> Result<(), VAStatus> {
+    fn destroy_surfaces(&self, surfaces: &[u32]) -> Result<(), VAStatus> {
+        let mut trace = self.trace();
+
         for surf in surfaces {
-            *self
-                .surfaces
-                .get_mut(*surf as usize)
-                .ok_or(VA_STATUS_ERROR_INVALID_SURFACE)? = None;
+            let surface = self.surfaces.remove(*surf)?;
+            // the memory behind it goes too, nothing else can name it
+            let buffer_id = lock(&surface).buffer_id;
+            self.buffers.remove(buffer_id)?;
+        }
+
+        if let Some(trace) = &mut trace {
+            trace.record(Record::DestroySurfaces {
+                ids: surfaces.to_vec(),
+            });
         }
         Ok(())
     }
 
-    fn get_config_attributes(&self, profile: i32, entrypoint: u32, configs: &mut [VAConfigAttrib]) {
+    fn get_config_attributes(
+        &self,
+        profile: i32,
+        entrypoint: u32,
+        configs: &mut [VAConfigAttrib],
+    ) -> Result<(), VAStatus> {
+        let cap = caps::find(profile, entrypoint)?;
         for c in configs {
-            match c.type_ {
-                VAConfigAttribType_VAConfigAttribRTFormat => {
-                    c.value = VA_RT_FORMAT_YUV420 as u32;
-                }
-                VAConfigAttribType_VAConfigAttribRateControl => {
-                    c.value = VA_RC_CBR as u32;
-                }
-                VAConfigAttribType_VAConfigAttribEncMaxRefFrames => {
-                    c.value = 10; // TODO(RG)!
+            c.value = match (c.type_, &cap.encode) {
+                (VAConfigAttribType_VAConfigAttribRTFormat, _) => cap.rt_formats,
+                (VAConfigAttribType_VAConfigAttribMaxPictureWidth, _) => cap.max_width,
+                (VAConfigAttribType_VAConfigAttribMaxPictureHeight, _) => cap.max_height,
+                (VAConfigAttribType_VAConfigAttribRateControl, Some(enc)) => enc.rc_modes,
+                (VAConfigAttribType_VAConfigAttribEncMaxRefFrames, Some(enc)) => enc.max_ref_frames,
+                (VAConfigAttribType_VAConfigAttribQPBlockSize, Some(enc)) => enc.qp_block_size,
+                (VAConfigAttribType_VAConfigAttribEncMaxTemporalLayers, Some(enc)) => {
+                    enc.max_temporal_layers
                 }
                 // TODO header stuff
-                _ => {
-                    c.value = VA_ATTRIB_NOT_SUPPORTED as u32;
-                }
-            }
+                _ => VA_ATTRIB_NOT_SUPPORTED as u32,
+            };
         }
+        Ok(())
     }
 
     fn acquire_buffer_handle(&self, buf_id: u32, mem_type: u32) -> Result<VABufferInfo, i32> {
         if mem_type == VA_SURFACE_ATTRIB_MEM_TYPE_DRM_PRIME as u32 {
-            let buffer = self
-                .buffers
-                .get(buf_id as usize)
-                .ok_or(VA_STATUS_ERROR_INVALID_BUFFER)?
-                .as_ref()
-                .ok_or(VA_STATUS_ERROR_INVALID_BUFFER)?;
-
-            match buffer {
+            let buffer = self.buffer(buf_id)?;
+            let buffer = lock(&buffer);
+
+            match &*buffer {
                 Buffer::Surface { buf, size, .. } => {
                     Ok(VABufferInfo {
-                        handle: buf.dmabuf.as_raw_fd() as usize,
+                        handle: buf.dmabuf()?.as_raw_fd() as usize,
                         type_: 0, // ???
                         mem_type,
                         mem_size: *size,
                         va_reserved: Default::default(),
                     })
                 }
-                _ => todo!(),
+                _ => Err(VA_STATUS_ERROR_UNSUPPORTED_BUFFERTYPE),
             }
         } else {
-            todo!()
+            Err(VA_STATUS_ERROR_UNSUPPORTED_MEMORY_TYPE)
         }
     }
 
@@ -1169,9 +2728,11 @@ impl Driver {
         descriptor: &mut VADRMPRIMESurfaceDescriptor,
     ) -> Result<(), VAStatus> {
         let surf = self.surface(surface_id)?;
+        let surf = lock(&surf);
         let buffer = self.buffer(surf.buffer_id)?;
+        let buffer = lock(&buffer);
 
-        let (buf, size) = match buffer {
+        let (buf, size) = match &*buffer {
             Buffer::Surface { buf, size, .. } => (buf, size),
             _ => return Err(VA_STATUS_ERROR_INVALID_SURFACE),
         };
@@ -1191,7 +2752,11 @@ impl Driver {
             num_objects: 1,
             objects: [
                 _VADRMPRIMESurfaceDescriptor__bindgen_ty_1 {
-                    fd: buf.dmabuf.try_clone().unwrap().into_raw_fd(),
+                    fd: buf
+                        .dmabuf()?
+                        .try_clone()
+                        .map_err(|_| VA_STATUS_ERROR_OPERATION_FAILED)?
+                        .into_raw_fd(),
                     size: *size as u32,
                     drm_format_modifier: 0, // ??
                 },
@@ -1202,7 +2767,7 @@ impl Driver {
             num_layers: 1,
             layers: [
                 _VADRMPRIMESurfaceDescriptor__bindgen_ty_2 {
-                    drm_format: surf.format.fourcc, // idk if this is ok
+                    drm_format: surf.layout().drm_format,
                     num_planes: surf.planes.len() as u32,
                     object_index: [0; 4],
                     offset,
@@ -1216,150 +2781,234 @@ impl Driver {
         Ok(())
     }
 
-    fn render_picture(
-        &mut self,
+    fn begin_picture(
+        &self,
         context: VAContextID,
-        buffers: &[VABufferID],
+        render_target: VASurfaceID,
     ) -> Result<(), VAStatus> {
-        let context = Driver::get_field_mut(&mut self.contexts, context)?;
-        let config = Driver::get_field(&self.configs, context.config_id)?;
-
-        let target = Driver::get_field(
-            &self.surfaces,
-            context
-                .render_target
-                .ok_or(VA_STATUS_ERROR_INVALID_SURFACE)?,
-        )?;
+        let mut trace = self.trace();
+
+        let context_id = context;
+        let context = self.context(context)?;
+        self.surface(render_target)?;
+        lock(&context).render_target = Some(render_target);
+
+        if let Some(trace) = &mut trace {
+            trace.record(Record::BeginPicture {
+                context: context_id,
+                target: render_target,
+            });
+        }
+        Ok(())
+    }
+
+    fn render_picture(&self, context: VAContextID, buffers: &[VABufferID]) -> Result<(), VAStatus> {
+        let mut trace = self.trace();
+
+        let context_id = context;
+        let context = self.context(context)?;
+        let mut context = lock(&context);
+        let profile = lock(&self.config(context.config_id)?).profile;
+
+        let render_target = context
+            .render_target
+            .ok_or(VA_STATUS_ERROR_INVALID_SURFACE)?;
+        // a copy, so no surface stays locked while others are looked at
+        let target = lock(&self.surface(render_target)?).clone();
+
+        // colour standard VPP wrote into the target with, if any
+        let mut output_color = None;
 
-        for buf in buffers {
-            match (
-                Driver::get_field(&self.buffers, *buf)?,
-                config.profile,
-                &mut context.data,
-            ) {
-                (Buffer::Surface { .. }, _, _) => todo!(),
+        for buf_id in buffers {
+            let buf = self.buffer(*buf_id)?;
+            let buf = lock(&buf);
+            match (&*buf, profile, &mut context.data) {
                 (Buffer::VppPipelineParameterBufferType(pic), _, ContextData::Proc) => {
-                    assert!(pic.output_region.is_null());
-                    assert_eq!(pic.num_filters, 0);
-                    assert!(pic.blend_state.is_null());
-                    assert!(pic.blend_state.is_null());
-                    assert!(pic.additional_outputs.is_null());
-
-                    let input_surface = Driver::get_field(&self.surfaces, pic.surface)?;
-
-                    let surface_region = unsafe { &*pic.surface_region };
-                    assert_eq!(target.width, surface_region.width as u32);
-                    assert_eq!(target.height, surface_region.height as u32);
-                    assert_eq!(surface_region.x, 0);
-                    assert_eq!(surface_region.y, 0);
-                    assert_eq!(input_surface.format.fourcc, VA_FOURCC_BGRX);
-                    assert_eq!(target.format.fourcc, VA_FOURCC_NV12);
-
-                    println!(
+                    // plain colour conversion only, no filters, blending or extra outputs
+                    if !pic.output_region.is_null()
+                        || pic.num_filters != 0
+                        || !pic.blend_state.is_null()
+                        || !pic.additional_outputs.is_null()
+                    {
+                        return Err(VA_STATUS_ERROR_UNIMPLEMENTED);
+                    }
+
+                    let input_surface = lock(&self.surface(pic.surface)?).clone();
+
+                    // no region means the whole surface. No scaling or cropping either
+                    if !pic.surface_region.is_null() {
+                        let surface_region = unsafe { &*pic.surface_region };
+                        if target.width != surface_region.width as u32
+                            || target.height != surface_region.height as u32
+                            || surface_region.x != 0
+                            || surface_region.y != 0
+                        {
+                            return Err(VA_STATUS_ERROR_UNIMPLEMENTED);
+                        }
+                    }
+                    if input_surface.width < target.width || input_surface.height < target.height {
+                        return Err(VA_STATUS_ERROR_UNIMPLEMENTED);
+                    }
+                    let cap =
+                        caps::find(VAProfile_VAProfileNone, VAEntrypoint_VAEntrypointVideoProc)?;
+                    if !cap.input_fourccs.contains(&input_surface.format.fourcc)
+                        || !cap.output_fourccs.contains(&target.format.fourcc)
+                    {
+                        return Err(VA_STATUS_ERROR_INVALID_IMAGE_FORMAT);
+                    }
+
+                    debug!(
                         "proc rendering {} -> {}",
                         input_surface.buffer_id, target.buffer_id
                     );
 
                     // todo!();
                     //     // let input_map =
-                    let ob = (&self.buffers[target.buffer_id as usize] as *const _) as usize;
-                    let [input_buffer, output_buffer] = Driver::get_field_elements(
-                        &mut self.buffers,
-                        [input_surface.buffer_id, target.buffer_id],
-                    )?;
-                    assert_eq!(ob, output_buffer as *const _ as usize);
-
-                    let input_map = input_buffer.map();
-                    let output_map = output_buffer.map_mut();
-
-                    let plane_delimiter = target.planes[0].pitch as usize * target.height as usize;
-                    let (y, uv) = output_map.split_at_mut(plane_delimiter);
-                    convert_image(
-                        surface_region.width.into(),
-                        surface_region.height.into(),
-                        &ImageFormat {
-                            pixel_format: PixelFormat::Bgra,
-                            color_space: dcp::ColorSpace::Rgb,
-                            num_planes: 1,
-                        },
-                        Some(&[input_surface.planes[0].pitch as usize]),
-                        &[input_map],
-                        &ImageFormat {
-                            pixel_format: PixelFormat::Nv12,
-                            color_space: dcp::ColorSpace::Bt601,
-                            num_planes: 2,
-                        },
-                        Some(&[
-                            target.planes[0].pitch as usize,
-                            target.planes[1].pitch as usize,
-                        ]),
-                        &mut [y, uv],
-                    )
-                    .unwrap();
+                    if input_surface.buffer_id == target.buffer_id {
+                        return Err(VA_STATUS_ERROR_INVALID_SURFACE);
+                    }
+                    let input_buffer = self.buffer(input_surface.buffer_id)?;
+                    let output_buffer = self.buffer(target.buffer_id)?;
+                    // in ID order, see table.rs
+                    let (input_buffer, mut output_buffer) =
+                        if input_surface.buffer_id < target.buffer_id {
+                            let input_buffer = lock(&input_buffer);
+                            (input_buffer, lock(&output_buffer))
+                        } else {
+                            let output_buffer = lock(&output_buffer);
+                            (lock(&input_buffer), output_buffer)
+                        };
+
+                    let input_map = input_buffer.map()?;
+                    let output_map = output_buffer.map_mut()?;
+                    if let Some(trace) = &mut trace {
+                        trace.record(Record::SurfaceData {
+                            id: pic.surface,
+                            data: input_map.to_vec(),
+                        });
+                    }
+
+                    if !input_surface.layout().yuv.is_empty() {
+                        // only repacking, the colours stay what they were
+                        format::convert(
+                            target.width,
+                            target.height,
+                            input_surface.layout(),
+                            &input_surface.planes,
+                            input_map,
+                            target.layout(),
+                            &target.planes,
+                            output_map,
+                        );
+                        output_color =
+                            Some((input_surface.color_standard, input_surface.full_range));
+                    } else {
+                        // anything we can't do ourselves gets BT.601
+                        let full_range =
+                            pic.output_color_properties.color_range as u32 == VA_SOURCE_RANGE_FULL;
+                        let (color_standard, color_space) =
+                            match (pic.output_color_standard, full_range) {
+                                (_VAProcColorStandardType_VAProcColorStandardBT709, false) => (
+                                    _VAProcColorStandardType_VAProcColorStandardBT709,
+                                    dcp::ColorSpace::Bt709,
+                                ),
+                                (_VAProcColorStandardType_VAProcColorStandardBT709, true) => (
+                                    _VAProcColorStandardType_VAProcColorStandardBT709,
+                                    dcp::ColorSpace::Bt709FR,
+                                ),
+                                (_, false) => (
+                                    _VAProcColorStandardType_VAProcColorStandardBT601,
+                                    dcp::ColorSpace::Bt601,
+                                ),
+                                (_, true) => (
+                                    _VAProcColorStandardType_VAProcColorStandardBT601,
+                                    dcp::ColorSpace::Bt601FR,
+                                ),
+                            };
+                        output_color = Some((color_standard, full_range));
+
+                        convert_from_rgb(
+                            &input_surface,
+                            input_map,
+                            color_space,
+                            &target,
+                            output_map,
+                        )?;
+                    }
                 }
-                (Buffer::CodedBufferSegment(_, _), _, _) => todo!(),
                 (
                     Buffer::EncSequenceParameter(spb),
                     VAProfile_VAProfileH264Main,
                     ContextData::Enc(enc),
                 ) => {
-                    println!("encoding -> {}", target.buffer_id);
-
-                    if enc.enc.is_none() {
-                        let render_target = Driver::get_field(
-                            &self.surfaces,
-                            context
-                                .render_target
-                                .ok_or(VA_STATUS_ERROR_INVALID_SURFACE)?,
-                        )?;
-
-                        enc.enc = Some(
-                            Setup::preset(Preset::Ultrafast, Tune::StillImage, false, true)
-                                .bitrate(i32::try_from(spb.bits_per_second).unwrap() / 1024)
-                                // .timebase(num, den)
-                                .build(
-                                    match render_target.format.fourcc {
-                                        VA_FOURCC_NV12 => Colorspace::NV12,
-                                        _ => todo!(),
-                                    },
-                                    render_target.width.try_into().unwrap(),
-                                    render_target.height.try_into().unwrap(),
-                                )
-                                .map_err(|_| VA_STATUS_ERROR_ENCODING_ERROR)?,
-                        );
-                    } else {
-                        // todo!()
-                    };
+                    debug!("encoding -> {}", target.buffer_id);
 
-                    // todo!()
-                }
-                (Buffer::EncSequenceParameter(spb), VAProfile_VAProfileH264Main, _) => {
-                    todo!()
+                    // the encoder is only built once the first frame comes in, so that
+                    // misc parameters (frame rate etc) sent after this can still be applied
+                    enc.seq = Some(*spb);
                 }
                 (
-                    Buffer::EncMiscParameter(emp),
+                    Buffer::EncMiscParameter { type_, data },
                     VAProfile_VAProfileH264Main,
-                    ContextData::Enc(_),
+                    ContextData::Enc(enc),
                 ) => {
-                    match emp.type_ {
+                    match *type_ {
                         VAEncMiscParameterType_VAEncMiscParameterTypeRateControl => {
-                            let rc = unsafe {
-                                *(emp.data.as_ptr() as *const VAEncMiscParameterRateControl)
-                            };
-                            // TOOD: do something wit this
-                            println!("discarding ratecontrol for now");
+                            let rc = Buffer::read_misc::<VAEncMiscParameterRateControl>(data)?;
+                            // per-layer rate control only makes sense with temporal layers
+                            if unsafe { rc.rc_flags.bits.temporal_id() } != 0 {
+                                return Err(VA_STATUS_ERROR_INVALID_PARAMETER);
+                            }
+                            enc.update_rc(|enc_rc| {
+                                enc_rc.bits_per_second = rc.bits_per_second;
+                                if rc.target_percentage != 0 {
+                                    enc_rc.target_percentage = rc.target_percentage.min(100);
+                                }
+                                if rc.window_size != 0 {
+                                    enc_rc.window_size = rc.window_size;
+                                }
+                                enc_rc.min_qp = rc.min_qp;
+                                enc_rc.max_qp = rc.max_qp;
+                            });
                         }
                         VAEncMiscParameterType_VAEncMiscParameterTypeHRD => {
-                            println!("discarding hrd");
+                            let hrd = Buffer::read_misc::<VAEncMiscParameterHRD>(data)?;
+                            enc.update_rc(|rc| {
+                                rc.hrd = Some((hrd.buffer_size, hrd.initial_buffer_fullness))
+                            });
+                        }
+                        VAEncMiscParameterType_VAEncMiscParameterTypeSkipFrame => {
+                            let sf = Buffer::read_misc::<VAEncMiscParameterSkipFrame>(data)?;
+                            if sf.skip_frame_flag > 2 {
+                                return Err(VA_STATUS_ERROR_INVALID_PARAMETER);
+                            }
+                            // see encode_picture
+                            enc.skip_frame =
+                                Some((sf.skip_frame_flag, sf.num_skip_frames, sf.size_skip_frames));
+                        }
+                        VAEncMiscParameterType_VAEncMiscParameterTypeTemporalLayerStructure => {
+                            let tls = Buffer::read_misc::<VAEncMiscParameterTemporalLayerStructure>(
+                                data,
+                            )?;
+                            // see VAConfigAttribEncMaxTemporalLayers, only one layer
+                            if tls.number_of_layers > 1 {
+                                return Err(VA_STATUS_ERROR_UNIMPLEMENTED);
+                            }
                         }
                         VAEncMiscParameterType_VAEncMiscParameterTypeFrameRate => {
-                            let rc = unsafe {
-                                *(emp.data.as_ptr() as *const VAEncMiscParameterFrameRate)
+                            let fr = Buffer::read_misc::<VAEncMiscParameterFrameRate>(data)?;
+
+                            // if the top 16 bits are set, it's a fraction: den << 16 | num
+                            let (num, den) = match fr.framerate >> 16 {
+                                0 => (fr.framerate, 1),
+                                den => (fr.framerate & 0xffff, den),
                             };
-                            // TOOD: do something wit this
-                            println!("discarding framerate for now");
+                            if num == 0 {
+                                return Err(VA_STATUS_ERROR_INVALID_PARAMETER);
+                            }
+                            enc.fps = Some((num, den));
                         }
-                        _ => todo!(),
+                        _ => return Err(VA_STATUS_ERROR_UNIMPLEMENTED),
                     }
                 }
                 (
@@ -1367,96 +3016,419 @@ impl Driver {
                     VAProfile_VAProfileH264Main,
                     ContextData::Enc(e),
                 ) => {
+                    // only looked at in end_picture, but a bad ID belongs to this call. So does
+                    // a CurrPic the reconstructed frame can't be written back into
+                    self.buffer(eps.coded_buf)?;
+                    if eps.CurrPic.picture_id != VA_INVALID_ID
+                        && lock(&self.surface(eps.CurrPic.picture_id)?)
+                            .layout()
+                            .yuv
+                            .is_empty()
+                    {
+                        return Err(VA_STATUS_ERROR_INVALID_SURFACE);
+                    }
                     e.coded_buf = Some(eps.coded_buf);
-                    println!("discarding PictureParameter for now...")
+                    e.pic = Some(*eps);
+                }
+                (Buffer::EncQp(qp_map), VAProfile_VAProfileH264Main, ContextData::Enc(enc)) => {
+                    // absolute QPs, which only line up with x264's when the application picks
+                    // the frame's QP too
+                    if enc.rc.mode != VA_RC_CQP {
+                        return Err(VA_STATUS_ERROR_INVALID_PARAMETER);
+                    }
+                    // filled in through vaMapBuffer, so only now do we know what's in it
+                    if let Some(trace) = &mut trace {
+                        trace.record(Record::BufferData {
+                            id: *buf_id,
+                            data: qp_map.clone(),
+                        });
+                    }
+                    enc.qp_map = Some(qp_map.clone());
                 }
                 (
                     Buffer::EncSliceParameter(esp),
                     VAProfile_VAProfileH264Main,
                     ContextData::Enc(enc),
                 ) => {
-                    let src_buf = Driver::get_field(&mut self.buffers, target.buffer_id)?.map();
-                    let (y, uv) = src_buf.split_at(target.planes[1].offset);
-
-                    let x264 = enc.enc.as_mut().unwrap();
-                    let data = x264
-                        .encode(
-                            0,
-                            x264::Image::new(
-                                Colorspace::NV12,
-                                target.width as i32,
-                                target.height as i32,
-                                &[
-                                    x264::Plane {
-                                        stride: target.planes[0].pitch as _,
-                                        data: y,
-                                    },
-                                    x264::Plane {
-                                        stride: target.planes[1].pitch as _,
-                                        data: uv,
-                                    },
-                                ],
-                            ),
-                        )
-                        .unwrap();
-
-                    // data.1.
-                    let buffer = Driver::get_field_mut(
-                        &mut self.buffers,
-                        enc.coded_buf.ok_or(VA_STATUS_ERROR_INVALID_BUFFER)?,
-                    )?;
-
-                    if let Buffer::CodedBufferSegment(raw_bytes, cbs) = buffer {
-                        raw_bytes.clear();
-                        raw_bytes.extend_from_slice(data.0.entirety());
-
-                        *cbs = VACodedBufferSegment {
-                            size: raw_bytes.len() as u32,
-                            bit_offset: 0,
-                            status: 0,
-                            reserved: 0,
-                            buf: raw_bytes.as_mut_ptr() as _,
-                            next: null_mut(),
-                            va_reserved: Default::default(),
-                        };
-                    } else {
-                        return Err(VA_STATUS_ERROR_INVALID_BUFFER);
+                    // x264 does its own slicing, so the first slice speaks for the picture.
+                    // Encoding waits for end_picture, once everything for the frame is in
+                    enc.slice.get_or_insert(*esp);
+                }
+
+                // coded buffers, surfaces, or parameters for some other profile or entrypoint
+                _ => return Err(VA_STATUS_ERROR_UNSUPPORTED_BUFFERTYPE),
+            }
+        }
+
+        if let Some((color_standard, full_range)) = output_color {
+            let target = self.surface(render_target)?;
+            let mut target = lock(&target);
+            target.color_standard = color_standard;
+            target.full_range = full_range;
+        }
+
+        if let Some(trace) = &mut trace {
+            trace.record(Record::RenderPicture {
+                context: context_id,
+                buffers: buffers.to_vec(),
+            });
+        }
+        Ok(())
+    }
+
+    fn end_picture(&self, context: VAContextID) -> Result<(), VAStatus> {
+        let mut trace = self.trace();
+
+        self.encode_picture(context, &mut trace)?;
+
+        if let Some(trace) = &mut trace {
+            trace.record(Record::EndPicture { context });
+        }
+        Ok(())
+    }
+
+    // everything for the picture has been rendered, so this is where encoding happens
+    fn encode_picture(
+        &self,
+        context: VAContextID,
+        trace: &mut Option<MutexGuard<'_, Recorder>>,
+    ) -> Result<(), VAStatus> {
+        let context = self.context(context)?;
+        let mut context = lock(&context);
+        let render_target = context.render_target.take();
+
+        let ContextData::Enc(enc) = &mut context.data else {
+            return Ok(());
+        };
+        let Some(slice) = enc.slice.take() else {
+            // nothing to encode, e.g. only parameters were sent
+            return Ok(());
+        };
+        let skip_frame = enc.skip_frame.take();
+        let coded_buf = enc.coded_buf.take().ok_or(VA_STATUS_ERROR_INVALID_BUFFER)?;
+        let pic = enc.pic.take().ok_or(VA_STATUS_ERROR_INVALID_PARAMETER)?;
+        let render_target = render_target.ok_or(VA_STATUS_ERROR_INVALID_SURFACE)?;
+        let target = lock(&self.surface(render_target)?).clone();
+
+        // until x264 hands back this frame, mapping the coded buffer gives nothing rather than
+        // whatever was in it last
+        lock(&self.buffer(coded_buf)?).write_coded(&[], 0, Default::default())?;
+
+        if let Some((1, num_skip_frames, size_skip_frames)) = skip_frame {
+            // the application skipped some frames before this one
+            enc.pts += enc.skipped_time(num_skip_frames, size_skip_frames);
+        }
+
+        let src_buf = self.buffer(target.buffer_id)?;
+        let src_buf = lock(&src_buf);
+        if let Some(trace) = trace {
+            trace.record(Record::SurfaceData {
+                id: render_target,
+                data: src_buf.map()?.to_vec(),
+            });
+        }
+
+        // x264 takes the 4:2:0 ones as they are, anything else is subsampled to I420 first. Main
+        // can't carry more chroma than that anyway
+        let src_map = src_buf.map()?;
+        let scratch;
+        let (colorspace, planes, data) = match target.format.fourcc {
+            VA_FOURCC_NV12 => (Colorspace::NV12, &target.planes[..], src_map),
+            VA_FOURCC_I420 => (Colorspace::I420, &target.planes[..], src_map),
+            VA_FOURCC_YV12 => (Colorspace::YV12, &target.planes[..], src_map),
+            _ => {
+                let i420 = format::layout(VA_FOURCC_I420).unwrap();
+                let (planes, size) = i420.allocate(target.width, target.height, 1);
+                let mut data = vec![0; size];
+                format::convert(
+                    target.width,
+                    target.height,
+                    target.layout(),
+                    &target.planes,
+                    src_map,
+                    i420,
+                    &planes,
+                    &mut data,
+                );
+                scratch = (planes, data);
+                (Colorspace::I420, &scratch.0[..], &scratch.1[..])
+            }
+        };
+        let image_planes: Vec<_> = planes
+            .iter()
+            .enumerate()
+            .map(|(i, plane)| x264::Plane {
+                stride: plane.pitch as _,
+                data: &data[plane.offset..planes.get(i + 1).map_or(data.len(), |next| next.offset)],
+            })
+            .collect();
+
+        let pts = enc.pts;
+        enc.pts += 1;
+
+        enc.encoder(&self.config, &target)?;
+        let i_type = enc.constrain_references(&pic, &slice, pts)?;
+        // with CQP the application picks the QP of every frame
+        let qp = (enc.rc.mode == VA_RC_CQP)
+            .then(|| i32::from(pic.pic_init_qp) + i32::from(slice.slice_qp_delta));
+        let quant_offsets = enc.quant_offsets(qp)?;
+
+        // x264 can't be made to write a frame of nothing but P_Skip macroblocks, so a skipped
+        // frame is only approximated: a P frame at the highest QP, where x264 skips most of what
+        // the reference already covers and spends as little as it can on the rest. It may still
+        // come out bigger than a real skipped frame. Either way it's a frame like any other for
+        // the references after it. An I frame can't repeat anything, so it isn't skipped
+        let (i_type, qp, quant_offsets) = match skip_frame {
+            Some((2, ..)) if i_type == X264_TYPE_AUTO as i32 => {
+                (X264_TYPE_P as i32, Some(SKIP_QP), None)
+            }
+            _ => (i_type, qp, quant_offsets),
+        };
+
+        let x264 = enc.enc.as_mut().unwrap();
+        if enc.rc_changed {
+            let rc = enc.rc;
+            x264.reconfig(|param| rc.apply(param))?;
+            enc.rc_changed = false;
+        }
+
+        // may be smaller than the surface, if it's bigger than the coded size
+        let (width, height) = (x264.width, x264.height);
+        let (bitstream, pic_out) = x264.encode(
+            pts,
+            i_type,
+            qp,
+            quant_offsets.as_deref(),
+            x264::Image::new(colorspace, width, height, &image_planes),
+        )?;
+        // CurrPic may well be the same surface
+        drop(src_buf);
+
+        enc.refs.insert(pic.CurrPic.picture_id, pts);
+        enc.held.push((pts, render_target, pic.CurrPic.picture_id));
+        enc.coded_bufs.push_back(coded_buf);
+
+        // x264 may be holding on to frames (see encoder()), so what comes out, if anything, can
+        // be an earlier one
+        if bitstream.is_empty() {
+            return Ok(());
+        }
+        self.deliver(enc, width, height, &bitstream, &pic_out)
+    }
+
+    // a frame x264 handed back goes to its coded buffer and CurrPic
+    fn deliver(
+        &self,
+        enc: &mut EncData,
+        width: i32,
+        height: i32,
+        bitstream: &[u8],
+        pic_out: &x264_picture_t,
+    ) -> Result<(), VAStatus> {
+        let held = enc
+            .held
+            .iter()
+            .position(|&(pts, ..)| pts == pic_out.i_pts)
+            .ok_or(VA_STATUS_ERROR_ENCODING_ERROR)?;
+        let (_, _, recon) = enc.held.remove(held);
+        let coded_buf = enc
+            .coded_bufs
+            .pop_front()
+            .ok_or(VA_STATUS_ERROR_ENCODING_ERROR)?;
+
+        // applications can read the reconstructed frame back out of CurrPic, which
+        // render_picture made sure is YUV. The frame is encoded by now, so nothing here fails it
+        if recon != VA_INVALID_ID && pic_out.img.i_plane == 2 {
+            // another thread may have destroyed it in the meantime
+            if let Ok(recon) = self.surface(recon) {
+                let recon = lock(&recon);
+                if let Ok(recon_buf) = self.buffer(recon.buffer_id) {
+                    if let Ok(map) = lock(&recon_buf).map_mut() {
+                        copy_recon(&pic_out.img, width as usize, height as usize, &recon, map);
                     }
                 }
+            }
+        }
+
+        // x264 hands back the average over the frame's macroblocks
+        let average_qp = (pic_out.i_qpplus1 - 1).max(0) as u32;
+        let frame_stats = CodedBufferStats {
+            frame_type: pic_out.i_type,
+            average_qp,
+            bits: bitstream.len() as u64 * 8,
+            psnr: pic_out.prop.f_psnr,
+            psnr_avg: pic_out.prop.f_psnr_avg,
+            ssim: pic_out.prop.f_ssim,
+        };
+
+        // same here. The slice and frame size flags only mean something with
+        // VAEncMiscParameterMaxSliceSize and the like, which we don't take
+        if let Ok(buf) = self.buffer(coded_buf) {
+            lock(&buf).write_coded(
+                bitstream,
+                average_qp & VA_CODED_BUF_STATUS_PICTURE_AVE_QP_MASK,
+                frame_stats,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    // x264 only lets go of the frames it's holding by ending the stream. They go out, and the
+    // next picture opens a new encoder, which starts over with an IDR
+    fn drain(&self, enc: &mut EncData) -> Result<(), VAStatus> {
+        if enc.held.is_empty() {
+            return Ok(());
+        }
+        let Some(mut x264) = enc.enc.take() else {
+            return Ok(());
+        };
+        enc.refs.clear();
+
+        let mut res = Ok(());
+        while res.is_ok() && x264.delayed_frames() > 0 {
+            res = x264.flush().and_then(|(bitstream, pic_out)| {
+                if bitstream.is_empty() {
+                    return Ok(());
+                }
+                self.deliver(enc, x264.width, x264.height, &bitstream, &pic_out)
+            });
+        }
+        enc.held.clear();
+        enc.coded_bufs.clear();
+        res
+    }
 
-                a => todo!("{a:?}"),
+    // drains every context `waiting` says has a frame the application is waiting on
+    fn drain_where(&self, waiting: impl Fn(&EncData) -> bool) -> Result<(), VAStatus> {
+        for context in self.contexts.all() {
+            if let ContextData::Enc(enc) = &mut lock(&context).data {
+                if waiting(enc) {
+                    self.drain(enc)?;
+                }
             }
         }
+        Ok(())
+    }
+
+    // whatever's encoding from or into the surface is done once this returns
+    fn sync_surface(&self, surface: VASurfaceID) -> Result<(), VAStatus> {
+        let mut trace = self.trace();
+
+        self.drain_where(|enc| {
+            enc.held
+                .iter()
+                .any(|&(_, target, recon)| target == surface || recon == surface)
+        })?;
+
+        if let Some(trace) = &mut trace {
+            trace.record(Record::SyncSurface { id: surface });
+        }
+        Ok(())
+    }
 
+    // the drain, if any, happens right here on the CPU, so there's no timeout to go by
+    fn sync_buffer(&self, buf_id: VABufferID) -> Result<(), VAStatus> {
+        let mut trace = self.trace();
+
+        self.finish_coded(buf_id)?;
+
+        if let Some(trace) = &mut trace {
+            trace.record(Record::SyncBuffer { id: buf_id });
+        }
         Ok(())
     }
 
-    fn sync_buffer(&self, buf_id: VABufferID, timeout_ns: u64) {}
+    // a coded buffer only has its frame once x264 has handed it back
+    fn finish_coded(&self, buf_id: VABufferID) -> Result<(), VAStatus> {
+        if matches!(*lock(&self.buffer(buf_id)?), Buffer::CodedBufferSegment(..)) {
+            self.drain_where(|enc| enc.coded_bufs.contains(&buf_id))?;
+        }
+        Ok(())
+    }
 
     fn create_buffer(
-        &mut self,
+        &self,
         context: u32,
         type_: u32,
         size: u32,
         num_elements: u32,
         data: Option<&[u8]>,
     ) -> Result<u32, i32> {
-        let id = self.buffers.len();
-        self.buffers
-            .push(Some(Buffer::from_type(type_, size, num_elements, data)?));
-        Ok(id as u32)
+        let mut trace = self.trace();
+
+        let id = self
+            .buffers
+            .insert(Buffer::from_type(type_, size, num_elements, data)?)?;
+
+        if let Some(trace) = &mut trace {
+            trace.record(Record::CreateBuffer {
+                context,
+                type_,
+                size,
+                num_elements,
+                data: data.map(<[u8]>::to_vec),
+                id,
+            });
+        }
+        Ok(id)
+    }
+
+    // NOTE: this is suuuuper sketchy and probably violates aliasing rules
+    // there's no way to enforce the aliasing rules though, unfortunately...altho I'm sure this could be improved
+    // the data doesn't move while the buffer exists, so the lock only needs to cover this
+    fn map_buffer(&self, buf_id: VABufferID) -> Result<*mut u8, VAStatus> {
+        let mut trace = self.trace();
+
+        // no lock on the buffer yet, draining writes to it
+        self.finish_coded(buf_id)?;
+        let map = lock(&self.buffer(buf_id)?).map_mut()?.as_mut_ptr();
+
+        if let Some(trace) = &mut trace {
+            trace.record(Record::MapBuffer { id: buf_id });
+        }
+        Ok(map)
+    }
+
+    fn destroy_buffer(&self, buf_id: VABufferID) -> Result<(), VAStatus> {
+        let mut trace = self.trace();
+
+        self.buffers.remove(buf_id)?;
+
+        if let Some(trace) = &mut trace {
+            trace.record(Record::DestroyBuffer { id: buf_id });
+        }
+        Ok(())
+    }
+
+    fn destroy_image(&self, image: VAImageID) -> Result<(), VAStatus> {
+        let mut trace = self.trace();
+
+        self.images.remove(image)?;
+
+        if let Some(trace) = &mut trace {
+            trace.record(Record::DestroyImage { id: image });
+        }
+        Ok(())
     }
 }
 
 // vpp
 impl Driver {
-    fn vpp_query_video_proc_pipeline_cpas(&self, pipeline_caps: &mut VAProcPipelineCaps) {
-        const INPUT_COLOR_STANDARDS: &[VAProcColorStandardType] =
-            &[_VAProcColorStandardType_VAProcColorStandardBT709];
-        const OUTPUT_COLOR_STANDARDS: &[VAProcColorStandardType] =
-            &[_VAProcColorStandardType_VAProcColorStandardBT709];
-        const INPUT_PIXEL_FORMATS: &[u32] = &[VA_FOURCC_BGRX];
-        const OUTPUT_PIXEL_FORMATS: &[u32] = &[VA_FOURCC_NV12];
+    fn vpp_query_video_proc_pipeline_cpas(
+        &self,
+        pipeline_caps: &mut VAProcPipelineCaps,
+    ) -> Result<(), VAStatus> {
+        let cap = caps::find(VAProfile_VAProfileNone, VAEntrypoint_VAEntrypointVideoProc)?;
+        // YUV input keeps whichever it has
+        const INPUT_COLOR_STANDARDS: &[VAProcColorStandardType] = &[
+            _VAProcColorStandardType_VAProcColorStandardBT601,
+            _VAProcColorStandardType_VAProcColorStandardBT709,
+        ];
+        const OUTPUT_COLOR_STANDARDS: &[VAProcColorStandardType] = &[
+            _VAProcColorStandardType_VAProcColorStandardBT601,
+            _VAProcColorStandardType_VAProcColorStandardBT709,
+        ];
         // https://intel.github.io/libva/structVAProcPipelineCaps.html#adca82f311a2b95bc40f799ba151db5e0
         *pipeline_caps = VAProcPipelineCaps {
             pipeline_flags: 0,
@@ -1471,108 +3443,297 @@ impl Driver {
             blend_flags: 0,
             mirror_flags: 0,
             num_additional_outputs: 0,
-            num_input_pixel_formats: INPUT_PIXEL_FORMATS.len() as u32,
-            input_pixel_format: INPUT_PIXEL_FORMATS.as_ptr() as _,
-            num_output_pixel_formats: OUTPUT_PIXEL_FORMATS.len() as u32,
-            output_pixel_format: OUTPUT_PIXEL_FORMATS.as_ptr() as _,
-            max_input_width: 16384,
-            max_input_height: 16384,
+            num_input_pixel_formats: cap.input_fourccs.len() as u32,
+            input_pixel_format: cap.input_fourccs.as_ptr() as _,
+            num_output_pixel_formats: cap.output_fourccs.len() as u32,
+            output_pixel_format: cap.output_fourccs.as_ptr() as _,
+            max_input_width: cap.max_width,
+            max_input_height: cap.max_height,
             min_input_width: 2,
             min_input_height: 2,
-            max_output_width: 16384,
-            max_output_height: 16384,
+            max_output_width: cap.max_width,
+            max_output_height: cap.max_height,
             min_output_width: 2,
             min_output_height: 2,
             va_reserved: Default::default(),
-        }
+        };
+        Ok(())
     }
 }
 
 // helpers
 impl Driver {
-    // TODO: don't just return invalid buffer for everything lmao
-    fn get_field<T>(vec: &Vec<Option<T>>, id: u32) -> Result<&T, VAStatus> {
-        vec.get(id as usize)
-            .ok_or(VA_STATUS_ERROR_INVALID_BUFFER)?
-            .as_ref()
-            .ok_or(VA_STATUS_ERROR_INVALID_BUFFER)
-    }
-    fn get_field_mut<T>(vec: &mut Vec<Option<T>>, id: u32) -> Result<&mut T, VAStatus> {
-        vec.get_mut(id as usize)
-            .ok_or(VA_STATUS_ERROR_INVALID_BUFFER)?
-            .as_mut()
-            .ok_or(VA_STATUS_ERROR_INVALID_BUFFER)
-    }
-
-    fn get_field_elements<T, const N: usize>(
-        vec: &mut Vec<Option<T>>,
-        mut indicies: [u32; N],
-    ) -> Result<[&mut T; N], VAStatus> {
-        // indicies.sort();
-        assert!(indicies.windows(2).all(|w| w[0] < w[1])); // unimplemented
-
-        let mut iter = vec.iter_mut();
-
-        array::try_from_fn(|out_idx| {
-            let in_idx = indicies[out_idx];
-            let distance_from_last_index = usize::try_from(if out_idx == 0 {
-                in_idx
-            } else {
-                in_idx - indicies[out_idx - 1] - 1
-            })
-            .unwrap();
+    // each table knows its own VA_STATUS_ERROR_INVALID_*
+    fn buffer(&self, id: u32) -> Result<Handle<Buffer>, VAStatus> {
+        self.buffers.get(id)
+    }
+    fn surface(&self, id: u32) -> Result<Handle<Surface>, VAStatus> {
+        self.surfaces.get(id)
+    }
+    fn config(&self, id: u32) -> Result<Handle<Config>, VAStatus> {
+        self.configs.get(id)
+    }
+    fn context(&self, id: u32) -> Result<Handle<Context>, VAStatus> {
+        self.contexts.get(id)
+    }
 
-            let r = iter
-                .nth(distance_from_last_index)
-                .map(|a| a.as_mut())
-                .flatten();
-            r
-        })
-        .ok_or(VA_STATUS_ERROR_INVALID_BUFFER)
+    // held for the whole of a traced call, see trace.rs
+    fn trace(&self) -> Option<MutexGuard<'_, Recorder>> {
+        self.trace.as_ref().map(lock)
+    }
+}
+
+// Not part of VA-API: lets applications that know they're on this driver get at the per-frame
+// stats x264 reports, which don't fit in VACodedBufferSegment::status. Look it up with dlsym
+#[no_mangle]
+#[allow(non_snake_case)]
+unsafe extern "C" fn vaX264QueryCodedBufferStats(
+    dpy: VADisplay,
+    buf_id: VABufferID,
+    stats: *mut CodedBufferStats,
+) -> VAStatus {
+    guard(
+        "vaX264QueryCodedBufferStats",
+        |_| format!("buf={buf_id}"),
+        || {
+            let ctx = (*(dpy as VADisplayContextP)).pDriverContext;
+            let driver = &*((*ctx).pDriverData as *const Driver);
+
+            let buf = match driver.buffer(buf_id) {
+                Ok(buf) => buf,
+                Err(e) => return e,
+            };
+            let buf = lock(&buf);
+
+            match &*buf {
+                Buffer::CodedBufferSegment(_, _, s) => {
+                    *stats = *s;
+                    VA_STATUS_SUCCESS
+                }
+                _ => VA_STATUS_ERROR_INVALID_BUFFER,
+            }
+        },
+    )
+}
+
+// Not part of VA-API either: plays back a trace recorded with LIBVA_X264_TRACE on a driver of its
+// own, no display needed, and writes the bitstream the application got to `output`
+#[no_mangle]
+#[allow(non_snake_case)]
+unsafe extern "C" fn vaX264ReplayTrace(
+    trace_path: *const c_char,
+    output_path: *const c_char,
+) -> VAStatus {
+    guard(
+        "vaX264ReplayTrace",
+        |_| {
+            format!(
+                "trace={:?} output={:?}",
+                CStr::from_ptr(trace_path),
+                CStr::from_ptr(output_path)
+            )
+        },
+        || {
+            let open = |path: *const c_char, create| {
+                let path = CStr::from_ptr(path)
+                    .to_str()
+                    .map_err(|_| VA_STATUS_ERROR_INVALID_PARAMETER)?;
+                if create {
+                    File::create(path)
+                } else {
+                    File::open(path)
+                }
+                .map_err(|e| {
+                    error!("can't open {path}: {e}");
+                    VA_STATUS_ERROR_OPERATION_FAILED
+                })
+            };
+
+            match open(trace_path, false)
+                .and_then(|trace| Ok((trace, open(output_path, true)?)))
+                .and_then(|(trace, output)| trace::replay(trace, output))
+            {
+                Ok(()) => VA_STATUS_SUCCESS,
+                Err(e) => e,
+            }
+        },
+    )
+}
+
+#[no_mangle]
+extern "C" fn __vaDriverInit_1_13(ctx: VADriverContextP) -> VAStatus {
+    guard(
+        "__vaDriverInit_1_13",
+        |_| String::new(),
+        || match unsafe { Driver::init_context(&mut *ctx) } {
+            Ok(_) => VA_STATUS_SUCCESS,
+            Err(e) => e,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr::null;
+
+    use super::*;
+
+    fn apply(rc: RateControl) -> x264_param_t {
+        // all zeroes is a valid x264_param_t, if not a useful one
+        let mut param = unsafe { mem::zeroed::<x264_param_t>() };
+        rc.apply(&mut param);
+        param
+    }
+
+    const MBPS: u32 = 1_000_000;
+
+    #[test]
+    fn cqp_keeps_aq_for_qp_maps() {
+        let param = apply(RateControl {
+            mode: VA_RC_CQP,
+            bits_per_second: 4 * MBPS,
+            ..Default::default()
+        });
+        assert_eq!(param.rc.i_rc_method, X264_RC_CRF as i32);
+        assert_eq!(param.rc.i_aq_mode, X264_AQ_VARIANCE as i32);
+        assert_eq!(param.rc.f_aq_strength, 0.0);
+        assert_eq!(param.rc.b_mb_tree, 0);
+        // the bitrate means nothing with CQP
+        assert_eq!(param.rc.i_bitrate, 0);
     }
 
-    fn buffer(&self, id: u32) -> Result<&Buffer, VAStatus> {
-        Driver::get_field(&self.buffers, id)
+    #[test]
+    fn cbr() {
+        let param = apply(RateControl {
+            bits_per_second: 4 * MBPS,
+            window_size: 500,
+            ..Default::default()
+        });
+        assert_eq!(param.rc.i_rc_method, X264_RC_ABR as i32);
+        assert_eq!(param.rc.i_bitrate, 4000);
+        assert_eq!(param.rc.i_vbv_max_bitrate, 4000);
+        assert_eq!(param.rc.i_vbv_buffer_size, 2000);
     }
-    fn buffer_mut(&mut self, id: u32) -> Result<&mut Buffer, VAStatus> {
-        self.buffers
-            .get_mut(id as usize)
-            .ok_or(VA_STATUS_ERROR_INVALID_BUFFER)?
-            .as_mut()
-            .ok_or(VA_STATUS_ERROR_INVALID_BUFFER)
+
+    #[test]
+    fn vbr_aims_below_the_peak() {
+        let param = apply(RateControl {
+            mode: VA_RC_VBR,
+            bits_per_second: 4 * MBPS,
+            target_percentage: 60,
+            ..Default::default()
+        });
+        assert_eq!(param.rc.i_bitrate, 2400);
+        assert_eq!(param.rc.i_vbv_max_bitrate, 4000);
     }
 
-    fn surface(&self, id: u32) -> Result<&Surface, VAStatus> {
-        self.surfaces
-            .get(id as usize)
-            .ok_or(VA_STATUS_ERROR_INVALID_BUFFER)?
-            .as_ref()
-            .ok_or(VA_STATUS_ERROR_INVALID_BUFFER)
+    #[test]
+    fn hrd_sets_the_buffer() {
+        let param = apply(RateControl {
+            bits_per_second: 4 * MBPS,
+            hrd: Some((8 * MBPS, 2 * MBPS)),
+            min_qp: 10,
+            max_qp: 40,
+            ..Default::default()
+        });
+        assert_eq!(param.rc.i_vbv_buffer_size, 8000);
+        assert_eq!(param.rc.f_vbv_buffer_init, 0.25);
+        assert_eq!((param.rc.i_qp_min, param.rc.i_qp_max), (10, 40));
     }
-    fn config(&self, id: u32) -> Result<&Config, VAStatus> {
-        Driver::get_field(&self.configs, id)
+
+    #[test]
+    fn no_bitrate_leaves_the_preset() {
+        let param = apply(RateControl::default());
+        assert_eq!(param.rc.i_rc_method, 0);
+        assert_eq!(param.rc.i_bitrate, 0);
+        assert_eq!(param.rc.i_vbv_buffer_size, 0);
     }
-    fn context(&self, id: u32) -> Result<&Context, VAStatus> {
-        self.contexts
-            .get(id as usize)
-            .ok_or(VA_STATUS_ERROR_INVALID_BUFFER)?
-            .as_ref()
-            .ok_or(VA_STATUS_ERROR_INVALID_BUFFER)
+
+    #[test]
+    fn huge_bitrates_saturate() {
+        let param = apply(RateControl {
+            bits_per_second: u32::MAX,
+            window_size: u32::MAX,
+            ..Default::default()
+        });
+        assert_eq!(param.rc.i_bitrate, (u32::MAX / 1000) as i32);
+        assert_eq!(param.rc.i_vbv_buffer_size, i32::MAX);
     }
-    fn context_mut(&mut self, id: u32) -> Result<&mut Context, VAStatus> {
-        self.contexts
-            .get_mut(id as usize)
-            .ok_or(VA_STATUS_ERROR_INVALID_BUFFER)?
-            .as_mut()
-            .ok_or(VA_STATUS_ERROR_INVALID_BUFFER)
+    #[test]
+    fn skipped_frames_spend_their_bits() {
+        let mut enc = EncData {
+            fps: Some((30, 1)),
+            ..Default::default()
+        };
+        enc.rc.bits_per_second = 300_000;
+        // 10000 bits a frame, and the remainder waits
+        assert_eq!(enc.skipped_time(3, 0), 3);
+        assert_eq!(enc.skipped_time(3, 15_000), 2);
+        assert_eq!(enc.skipped_time(1, 5_000), 0);
+        assert_eq!(enc.skipped_bits, 0);
+        // no more than the frames skipped
+        assert_eq!(enc.skipped_time(1, 25_000), 0);
+        assert_eq!(enc.skipped_bits, 15_000);
+
+        // nothing to count them against with CQP
+        enc.rc.mode = VA_RC_CQP;
+        assert_eq!(enc.skipped_time(2, 25_000), 2);
+        assert_eq!(enc.skipped_bits, 0);
     }
-}
 
-#[no_mangle]
-extern "C" fn __vaDriverInit_1_13(ctx: VADriverContextP) -> VAStatus {
-    unsafe {
-        Driver::init_context(&mut *ctx);
+    #[test]
+    fn app_arrays() {
+        let ids = [1u32, 2];
+        unsafe {
+            assert_eq!(app_slice(ids.as_ptr(), 2i32), Ok(&ids[..]));
+            assert_eq!(
+                app_slice(ids.as_ptr(), -1i32),
+                Err(VA_STATUS_ERROR_INVALID_PARAMETER)
+            );
+            assert_eq!(app_slice(null::<u32>(), 0i32), Ok(&[][..]));
+            assert_eq!(
+                app_slice(null::<u32>(), 1u32),
+                Err(VA_STATUS_ERROR_INVALID_PARAMETER)
+            );
+        }
+    }
+
+    #[test]
+    fn buffer_sizes_that_overflow() {
+        for type_ in [
+            VABufferType_VAEncQPBufferType,
+            VABufferType_VAEncMacroblockMapBufferType,
+        ] {
+            let buffer = Buffer::from_type(type_, u32::MAX, 2, None);
+            assert_eq!(buffer.err(), Some(VA_STATUS_ERROR_INVALID_PARAMETER));
+        }
     }
 
-    VA_STATUS_SUCCESS
+    #[test]
+    fn unaligned_parameters() {
+        let data = [0, 1, 0, 0, 0];
+        assert_eq!(Buffer::from_type_t::<u32>(4, 1, Some(&data[1..])), Ok(1));
+        // shorter than it says
+        assert!(Buffer::from_type_t::<u32>(4, 1, Some(&data[2..])).is_err());
+    }
+
+    #[test]
+    fn no_10_bit_yuv() {
+        // there's no P010 layout, so nothing may offer YUV420_10
+        assert!(caps::CAPABILITIES
+            .iter()
+            .all(|cap| cap.rt_formats & VA_RT_FORMAT_YUV420_10 == 0));
+        assert_eq!(default_fourcc(VA_RT_FORMAT_YUV420_10), None);
+
+        // a driver needs /dev/udmabuf
+        let Ok(driver) = Driver::new(DriverConfig::default(), None) else {
+            eprintln!("no /dev/udmabuf, skipping vaCreateSurfaces");
+            return;
+        };
+        let mut surfaces = [0; 1];
+        assert_eq!(
+            driver.create_surfaces(VA_RT_FORMAT_YUV420_10, 64, 64, &mut surfaces, &[]),
+            Err(VA_STATUS_ERROR_UNSUPPORTED_RT_FORMAT)
+        );
+    }
 }
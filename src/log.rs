@@ -0,0 +1,116 @@
+// Driver logging, off unless LIBVA_X264_LOG names a level:
+//
+//     LIBVA_X264_LOG=debug                 error, warn, info or debug
+//     LIBVA_X264_LOG_FILE=/tmp/va.log      append here instead of stderr
+//
+// debug gets one line per vtable call, with its arguments and the VAStatus it returned. Nothing
+// ever goes to stdout, applications pipe bitstreams through that.
+
+use std::{
+    env, fmt,
+    fs::OpenOptions,
+    io::{self, Write},
+    sync::{Mutex, OnceLock},
+};
+
+use crate::table::lock;
+
+const LEVEL_VAR: &str = "LIBVA_X264_LOG";
+const FILE_VAR: &str = "LIBVA_X264_LOG_FILE";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "error" => Level::Error,
+            "warn" => Level::Warn,
+            "info" => Level::Info,
+            "debug" => Level::Debug,
+            _ => return None,
+        })
+    }
+}
+
+struct Logger {
+    level: Option<Level>, // None is off
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+fn logger() -> &'static Logger {
+    LOGGER.get_or_init(|| {
+        let level = env::var(LEVEL_VAR).ok();
+        let out: Box<dyn Write + Send> = match env::var_os(FILE_VAR) {
+            Some(path) => match OpenOptions::new().create(true).append(true).open(&path) {
+                Ok(file) => Box::new(file),
+                Err(e) => {
+                    let _ = writeln!(
+                        io::stderr(),
+                        "libva-x264: can't open log file {}: {e}",
+                        path.to_string_lossy()
+                    );
+                    Box::new(io::stderr())
+                }
+            },
+            None => Box::new(io::stderr()),
+        };
+
+        Logger {
+            level: level
+                .as_deref()
+                .and_then(|level| match Level::parse(level) {
+                    Some(level) => Some(level),
+                    None => {
+                        let _ = writeln!(
+                            io::stderr(),
+                            "libva-x264: unknown {LEVEL_VAR} level `{level}`, logging errors only"
+                        );
+                        Some(Level::Error)
+                    }
+                }),
+            out: Mutex::new(out),
+        }
+    })
+}
+
+pub fn enabled(level: Level) -> bool {
+    logger().level.is_some_and(|max| level <= max)
+}
+
+pub fn write(level: Level, args: fmt::Arguments) {
+    let mut out = lock(&logger().out);
+    // nowhere left to report a failed log write
+    let _ = writeln!(out, "libva-x264 {level:?}: {args}");
+}
+
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::log::enabled($level) {
+            $crate::log::write($level, format_args!($($arg)*));
+        }
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => { log!($crate::log::Level::Error, $($arg)*) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => { log!($crate::log::Level::Warn, $($arg)*) };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { log!($crate::log::Level::Info, $($arg)*) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => { log!($crate::log::Level::Debug, $($arg)*) };
+}
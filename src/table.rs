@@ -0,0 +1,226 @@
+// ID -> object tables shared by every thread calling into the driver. The table lock is only
+// held long enough to find an object and clone out its handle, and every object has a lock of its
+// own, so calls on different contexts (buffers, ...) don't wait on each other. Destroying an
+// object that another thread is still using is fine too, it lives until that thread lets go.
+//
+// To stay clear of deadlocks, locks are taken in this order: the trace (see trace.rs), a context,
+// the buffers passed to vaRenderPicture, surfaces, then the buffers backing surfaces. No two
+// surfaces are held at once, and two surface buffers only ever in ID order.
+//
+// Slots are reused once their object is destroyed. An ID is the slot index in the low 16 bits
+// and the slot's generation in the high 16, and the generation goes up every time the slot is
+// freed, so an ID that outlived its object gets an error instead of whatever took the slot next.
+// Freed slots queue up and the longest free goes first, so it takes 65536 reuses of every free
+// slot, not just of one, before a stale ID can match again.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use crate::sys::{VAStatus, VA_STATUS_ERROR_ALLOCATION_FAILED};
+
+pub type Handle<T> = Arc<Mutex<T>>;
+
+const INDEX_BITS: u32 = 16;
+const INDEX_MASK: u32 = (1 << INDEX_BITS) - 1;
+// the last index is never handed out, so no ID can come out as VA_INVALID_ID
+const MAX_SLOTS: usize = INDEX_MASK as usize;
+
+#[derive(Debug)]
+struct Slot<T> {
+    generation: u16,
+    object: Option<Handle<T>>,
+}
+
+#[derive(Debug)]
+struct Slots<T> {
+    slots: Vec<Slot<T>>,
+    free: VecDeque<u32>,
+}
+
+#[derive(Debug)]
+pub struct Table<T> {
+    slots: RwLock<Slots<T>>,
+    invalid: VAStatus, // what a bad ID gets, VA_STATUS_ERROR_INVALID_SURFACE etc
+}
+
+impl<T> Table<T> {
+    pub fn new(invalid: VAStatus) -> Self {
+        Self {
+            slots: RwLock::new(Slots {
+                slots: Vec::new(),
+                free: VecDeque::new(),
+            }),
+            invalid,
+        }
+    }
+
+    pub fn insert(&self, value: T) -> Result<u32, VAStatus> {
+        let mut guard = self.write();
+        let slots = &mut *guard;
+
+        let index = match slots.free.pop_front() {
+            Some(index) => index,
+            None if slots.slots.len() < MAX_SLOTS => {
+                slots.slots.push(Slot {
+                    generation: 0,
+                    object: None,
+                });
+                (slots.slots.len() - 1) as u32
+            }
+            None => return Err(VA_STATUS_ERROR_ALLOCATION_FAILED),
+        };
+
+        let slot = &mut slots.slots[index as usize];
+        slot.object = Some(Arc::new(Mutex::new(value)));
+        Ok(u32::from(slot.generation) << INDEX_BITS | index)
+    }
+
+    pub fn get(&self, id: u32) -> Result<Handle<T>, VAStatus> {
+        match self.read().slots.get((id & INDEX_MASK) as usize) {
+            Some(Slot {
+                generation,
+                object: Some(object),
+            }) if u32::from(*generation) == id >> INDEX_BITS => Ok(object.clone()),
+            _ => Err(self.invalid),
+        }
+    }
+
+    pub fn remove(&self, id: u32) -> Result<Handle<T>, VAStatus> {
+        let mut guard = self.write();
+        let slots = &mut *guard;
+
+        let index = id & INDEX_MASK;
+        let slot = match slots.slots.get_mut(index as usize) {
+            Some(slot) if u32::from(slot.generation) == id >> INDEX_BITS => slot,
+            _ => return Err(self.invalid),
+        };
+        let object = slot.object.take().ok_or(self.invalid)?;
+
+        slot.generation = slot.generation.wrapping_add(1);
+        slots.free.push_back(index);
+        Ok(object)
+    }
+
+    // every object in the table right now, for the odd call that has to look through them all
+    pub fn all(&self) -> Vec<Handle<T>> {
+        self.read()
+            .slots
+            .iter()
+            .filter_map(|slot| slot.object.clone())
+            .collect()
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Slots<T>> {
+        self.slots.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Slots<T>> {
+        self.slots.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+// a panic while something was locked shouldn't take every later call on it down too
+pub fn lock<T>(object: &Mutex<T>) -> MutexGuard<'_, T> {
+    object.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sys::VA_STATUS_ERROR_INVALID_SURFACE;
+
+    #[test]
+    fn get_and_remove() {
+        let table = Table::new(VA_STATUS_ERROR_INVALID_SURFACE);
+        let a = table.insert("a").unwrap();
+        let b = table.insert("b").unwrap();
+        assert_ne!(a, b);
+        assert_eq!(*lock(&table.get(a).unwrap()), "a");
+        assert_eq!(*lock(&table.get(b).unwrap()), "b");
+
+        assert_eq!(*lock(&table.remove(a).unwrap()), "a");
+        assert_eq!(table.get(a).unwrap_err(), VA_STATUS_ERROR_INVALID_SURFACE);
+        assert_eq!(
+            table.remove(a).unwrap_err(),
+            VA_STATUS_ERROR_INVALID_SURFACE
+        );
+        assert_eq!(*lock(&table.get(b).unwrap()), "b");
+    }
+
+    #[test]
+    fn all_objects() {
+        let table = Table::new(VA_STATUS_ERROR_INVALID_SURFACE);
+        let ids: Vec<_> = (0..3).map(|i| table.insert(i).unwrap()).collect();
+        table.remove(ids[1]).unwrap();
+
+        let all: Vec<_> = table.all().iter().map(|object| *lock(object)).collect();
+        assert_eq!(all, [0, 2]);
+    }
+
+    #[test]
+    fn unknown_ids() {
+        let table = Table::<()>::new(VA_STATUS_ERROR_INVALID_SURFACE);
+        assert!(table.get(0).is_err());
+        assert!(table.get(u32::MAX).is_err());
+        assert!(table.remove(u32::MAX).is_err());
+    }
+
+    #[test]
+    fn stale_id_after_reuse() {
+        let table = Table::new(VA_STATUS_ERROR_INVALID_SURFACE);
+        let old = table.insert(1).unwrap();
+        table.remove(old).unwrap();
+        let new = table.insert(2).unwrap();
+
+        // same slot, next generation
+        assert_eq!(old & INDEX_MASK, new & INDEX_MASK);
+        assert_ne!(old, new);
+        assert!(table.get(old).is_err());
+        assert!(table.remove(old).is_err());
+        assert_eq!(*lock(&table.get(new).unwrap()), 2);
+    }
+
+    #[test]
+    fn oldest_free_slot_first() {
+        let table = Table::new(VA_STATUS_ERROR_INVALID_SURFACE);
+        let ids: Vec<_> = (0..3).map(|i| table.insert(i).unwrap()).collect();
+        table.remove(ids[1]).unwrap();
+        table.remove(ids[0]).unwrap();
+
+        let next = table.insert(3).unwrap();
+        assert_eq!(next & INDEX_MASK, ids[1] & INDEX_MASK);
+        let next = table.insert(4).unwrap();
+        assert_eq!(next & INDEX_MASK, ids[0] & INDEX_MASK);
+    }
+
+    #[test]
+    fn churn_keeps_ids_apart() {
+        // one object created and destroyed over and over, with a second slot free
+        let table = Table::new(VA_STATUS_ERROR_INVALID_SURFACE);
+        let spare = table.insert(()).unwrap();
+        let first = table.insert(()).unwrap();
+        table.remove(spare).unwrap();
+
+        let mut id = first;
+        for _ in 0..=u16::MAX {
+            table.remove(id).unwrap();
+            id = table.insert(()).unwrap();
+        }
+        // both slots took turns, so neither has wrapped yet
+        assert!(table.get(first).is_err());
+    }
+
+    #[test]
+    fn never_invalid_id() {
+        let table = Table::new(VA_STATUS_ERROR_INVALID_SURFACE);
+        for _ in 0..MAX_SLOTS {
+            assert_ne!(table.insert(()).unwrap() & INDEX_MASK, INDEX_MASK);
+        }
+        assert_eq!(
+            table.insert(()).unwrap_err(),
+            VA_STATUS_ERROR_ALLOCATION_FAILED
+        );
+    }
+}
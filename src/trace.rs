@@ -0,0 +1,833 @@
+// Call traces, for reproducing what an application did without the application. With
+//
+//     LIBVA_X264_TRACE=/tmp/app.trace
+//
+// every call that changes driver state is appended to that file once it has succeeded, along with
+// whatever the application wrote into memory we share with it: surface contents when the driver
+// reads them and QP maps when they're rendered. vaX264ReplayTrace then plays the file back on a
+// fresh driver, writing out whatever the application read from its coded buffers.
+//
+// Replay has to hand out the same IDs as the original run, parameter buffers name surfaces and
+// coded buffers by ID. So the trace stays locked for the whole of a traced call, which makes it
+// the first lock taken (see table.rs), and calls from different threads can't get recorded out of
+// order. It's a debugging aid, that costs nothing when the variable isn't set.
+//
+// Surfaces imported from the application (DRM PRIME, user pointers) replay as ones the driver
+// allocates itself, with the same planes at the same offsets in a buffer of the same size, so
+// their recorded contents go back in as they were.
+//
+// The format is plain little-endian: a header with the driver options, then one record per call.
+
+use std::{
+    env,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    ptr::null_mut,
+    sync::Mutex,
+};
+
+use crate::{config::DriverConfig, format, sys::*, table::lock, Buffer, Driver, PlaneInfo};
+
+const TRACE_VAR: &str = "LIBVA_X264_TRACE";
+
+const MAGIC: &[u8; 8] = b"VAX264TR";
+const VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum Record {
+    CreateConfig {
+        profile: VAProfile,
+        entrypoint: VAEntrypoint,
+        attribs: Vec<(u32, u32)>, // (type, value)
+        id: VAConfigID,
+    },
+    DestroyConfig {
+        id: VAConfigID,
+    },
+    CreateSurfaces {
+        format: u32,
+        width: u32,
+        height: u32,
+        fourcc: u32, // 0 if not given
+        ids: Vec<VASurfaceID>,
+    },
+    // one of the surfaces of a vaCreateSurfaces over the application's memory
+    ImportSurface {
+        mem_type: u32, // VA_SURFACE_ATTRIB_MEM_TYPE_*, for reading the trace only
+        width: u32,
+        height: u32,
+        fourcc: u32,
+        planes: Vec<PlaneInfo>,
+        size: u32, // of the whole buffer
+        id: VASurfaceID,
+    },
+    DestroySurfaces {
+        ids: Vec<VASurfaceID>,
+    },
+    CreateContext {
+        config: VAConfigID,
+        width: i32,
+        height: i32,
+        flag: i32,
+        render_targets: Vec<VASurfaceID>,
+        id: VAContextID,
+    },
+    DestroyContext {
+        id: VAContextID,
+    },
+    CreateBuffer {
+        context: VAContextID,
+        type_: VABufferType,
+        size: u32,
+        num_elements: u32,
+        data: Option<Vec<u8>>,
+        id: VABufferID,
+    },
+    DestroyBuffer {
+        id: VABufferID,
+    },
+    MapBuffer {
+        id: VABufferID,
+    },
+    DeriveImage {
+        surface: VASurfaceID,
+        id: VAImageID,
+    },
+    DestroyImage {
+        id: VAImageID,
+    },
+    BeginPicture {
+        context: VAContextID,
+        target: VASurfaceID,
+    },
+    RenderPicture {
+        context: VAContextID,
+        buffers: Vec<VABufferID>,
+    },
+    EndPicture {
+        context: VAContextID,
+    },
+    // contents the application put there itself, written back before the call that follows
+    BufferData {
+        id: VABufferID,
+        data: Vec<u8>,
+    },
+    SurfaceData {
+        id: VASurfaceID,
+        data: Vec<u8>,
+    },
+    SyncSurface {
+        id: VASurfaceID,
+    },
+    SyncBuffer {
+        id: VABufferID,
+    },
+}
+
+#[derive(Debug)]
+pub struct Recorder {
+    out: BufWriter<File>,
+    failed: bool,
+}
+
+impl Recorder {
+    pub fn from_env(config: &DriverConfig) -> Option<Mutex<Recorder>> {
+        let path = env::var_os(TRACE_VAR)?;
+        let mut recorder = Recorder {
+            out: match File::create(&path) {
+                Ok(file) => BufWriter::new(file),
+                Err(e) => {
+                    error!("can't create trace {}: {e}", path.to_string_lossy());
+                    return None;
+                }
+            },
+            failed: false,
+        };
+
+        if let Err(e) = write_header(&mut recorder.out, &config.opts) {
+            error!("can't write trace: {e}");
+            return None;
+        }
+        info!("tracing to {}", path.to_string_lossy());
+        Some(Mutex::new(recorder))
+    }
+
+    // a trace that's missing a call is worse than no trace, so the first error stops it
+    pub fn record(&mut self, record: Record) {
+        if self.failed {
+            return;
+        }
+
+        let res = record.write(&mut self.out).and_then(|_| match record {
+            // a frame at a time, so a crash loses as little as possible
+            Record::EndPicture { .. } => self.out.flush(),
+            _ => Ok(()),
+        });
+        if let Err(e) = res {
+            error!("can't write trace, stopping it: {e}");
+            self.failed = true;
+        }
+    }
+}
+
+fn write_header(out: &mut impl Write, opts: &[String]) -> io::Result<()> {
+    out.write_all(MAGIC)?;
+    put_u32(out, VERSION)?;
+    put_u32(out, opts.len() as u32)?;
+    for opt in opts {
+        put_bytes(out, opt.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_header(input: &mut impl Read) -> io::Result<Vec<String>> {
+    let mut magic = [0; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC || get_u32(input)? != VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a trace"));
+    }
+
+    (0..get_u32(input)?)
+        .map(|_| {
+            String::from_utf8(get_bytes(input)?)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        })
+        .collect()
+}
+
+impl Record {
+    fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        match self {
+            Record::CreateConfig {
+                profile,
+                entrypoint,
+                attribs,
+                id,
+            } => {
+                put_u32(out, 0)?;
+                put_u32(out, *profile as u32)?;
+                put_u32(out, *entrypoint)?;
+                put_u32(out, attribs.len() as u32)?;
+                for (type_, value) in attribs {
+                    put_u32(out, *type_)?;
+                    put_u32(out, *value)?;
+                }
+                put_u32(out, *id)
+            }
+            Record::DestroyConfig { id } => {
+                put_u32(out, 1)?;
+                put_u32(out, *id)
+            }
+            Record::CreateSurfaces {
+                format,
+                width,
+                height,
+                fourcc,
+                ids,
+            } => {
+                put_u32(out, 2)?;
+                put_u32(out, *format)?;
+                put_u32(out, *width)?;
+                put_u32(out, *height)?;
+                put_u32(out, *fourcc)?;
+                put_ids(out, ids)
+            }
+            Record::DestroySurfaces { ids } => {
+                put_u32(out, 3)?;
+                put_ids(out, ids)
+            }
+            Record::CreateContext {
+                config,
+                width,
+                height,
+                flag,
+                render_targets,
+                id,
+            } => {
+                put_u32(out, 4)?;
+                put_u32(out, *config)?;
+                put_u32(out, *width as u32)?;
+                put_u32(out, *height as u32)?;
+                put_u32(out, *flag as u32)?;
+                put_ids(out, render_targets)?;
+                put_u32(out, *id)
+            }
+            Record::DestroyContext { id } => {
+                put_u32(out, 5)?;
+                put_u32(out, *id)
+            }
+            Record::CreateBuffer {
+                context,
+                type_,
+                size,
+                num_elements,
+                data,
+                id,
+            } => {
+                put_u32(out, 6)?;
+                put_u32(out, *context)?;
+                put_u32(out, *type_)?;
+                put_u32(out, *size)?;
+                put_u32(out, *num_elements)?;
+                match data {
+                    Some(data) => {
+                        put_u32(out, 1)?;
+                        put_bytes(out, data)?;
+                    }
+                    None => put_u32(out, 0)?,
+                }
+                put_u32(out, *id)
+            }
+            Record::DestroyBuffer { id } => {
+                put_u32(out, 7)?;
+                put_u32(out, *id)
+            }
+            Record::MapBuffer { id } => {
+                put_u32(out, 8)?;
+                put_u32(out, *id)
+            }
+            Record::DeriveImage { surface, id } => {
+                put_u32(out, 9)?;
+                put_u32(out, *surface)?;
+                put_u32(out, *id)
+            }
+            Record::DestroyImage { id } => {
+                put_u32(out, 10)?;
+                put_u32(out, *id)
+            }
+            Record::BeginPicture { context, target } => {
+                put_u32(out, 11)?;
+                put_u32(out, *context)?;
+                put_u32(out, *target)
+            }
+            Record::RenderPicture { context, buffers } => {
+                put_u32(out, 12)?;
+                put_u32(out, *context)?;
+                put_ids(out, buffers)
+            }
+            Record::EndPicture { context } => {
+                put_u32(out, 13)?;
+                put_u32(out, *context)
+            }
+            Record::BufferData { id, data } => {
+                put_u32(out, 14)?;
+                put_u32(out, *id)?;
+                put_bytes(out, data)
+            }
+            Record::SurfaceData { id, data } => {
+                put_u32(out, 15)?;
+                put_u32(out, *id)?;
+                put_bytes(out, data)
+            }
+            Record::ImportSurface {
+                mem_type,
+                width,
+                height,
+                fourcc,
+                planes,
+                size,
+                id,
+            } => {
+                put_u32(out, 16)?;
+                put_u32(out, *mem_type)?;
+                put_u32(out, *width)?;
+                put_u32(out, *height)?;
+                put_u32(out, *fourcc)?;
+                put_u32(out, planes.len() as u32)?;
+                for plane in planes {
+                    put_u32(out, plane.pitch as u32)?;
+                    put_u32(out, plane.offset as u32)?;
+                }
+                put_u32(out, *size)?;
+                put_u32(out, *id)
+            }
+            Record::SyncSurface { id } => {
+                put_u32(out, 17)?;
+                put_u32(out, *id)
+            }
+            Record::SyncBuffer { id } => {
+                put_u32(out, 18)?;
+                put_u32(out, *id)
+            }
+        }
+    }
+
+    // None once the trace ends cleanly, between records
+    fn read(input: &mut impl Read) -> io::Result<Option<Record>> {
+        let mut tag = [0; 4];
+        match input.read_exact(&mut tag) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        Ok(Some(match u32::from_le_bytes(tag) {
+            0 => Record::CreateConfig {
+                profile: get_u32(input)? as VAProfile,
+                entrypoint: get_u32(input)?,
+                attribs: (0..get_u32(input)?)
+                    .map(|_| Ok((get_u32(input)?, get_u32(input)?)))
+                    .collect::<io::Result<_>>()?,
+                id: get_u32(input)?,
+            },
+            1 => Record::DestroyConfig {
+                id: get_u32(input)?,
+            },
+            2 => Record::CreateSurfaces {
+                format: get_u32(input)?,
+                width: get_u32(input)?,
+                height: get_u32(input)?,
+                fourcc: get_u32(input)?,
+                ids: get_ids(input)?,
+            },
+            3 => Record::DestroySurfaces {
+                ids: get_ids(input)?,
+            },
+            4 => Record::CreateContext {
+                config: get_u32(input)?,
+                width: get_u32(input)? as i32,
+                height: get_u32(input)? as i32,
+                flag: get_u32(input)? as i32,
+                render_targets: get_ids(input)?,
+                id: get_u32(input)?,
+            },
+            5 => Record::DestroyContext {
+                id: get_u32(input)?,
+            },
+            6 => Record::CreateBuffer {
+                context: get_u32(input)?,
+                type_: get_u32(input)?,
+                size: get_u32(input)?,
+                num_elements: get_u32(input)?,
+                data: match get_u32(input)? {
+                    0 => None,
+                    _ => Some(get_bytes(input)?),
+                },
+                id: get_u32(input)?,
+            },
+            7 => Record::DestroyBuffer {
+                id: get_u32(input)?,
+            },
+            8 => Record::MapBuffer {
+                id: get_u32(input)?,
+            },
+            9 => Record::DeriveImage {
+                surface: get_u32(input)?,
+                id: get_u32(input)?,
+            },
+            10 => Record::DestroyImage {
+                id: get_u32(input)?,
+            },
+            11 => Record::BeginPicture {
+                context: get_u32(input)?,
+                target: get_u32(input)?,
+            },
+            12 => Record::RenderPicture {
+                context: get_u32(input)?,
+                buffers: get_ids(input)?,
+            },
+            13 => Record::EndPicture {
+                context: get_u32(input)?,
+            },
+            14 => Record::BufferData {
+                id: get_u32(input)?,
+                data: get_bytes(input)?,
+            },
+            15 => Record::SurfaceData {
+                id: get_u32(input)?,
+                data: get_bytes(input)?,
+            },
+            16 => Record::ImportSurface {
+                mem_type: get_u32(input)?,
+                width: get_u32(input)?,
+                height: get_u32(input)?,
+                fourcc: get_u32(input)?,
+                planes: (0..get_u32(input)?)
+                    .map(|_| {
+                        Ok(PlaneInfo {
+                            pitch: get_u32(input)? as usize,
+                            offset: get_u32(input)? as usize,
+                        })
+                    })
+                    .collect::<io::Result<_>>()?,
+                size: get_u32(input)?,
+                id: get_u32(input)?,
+            },
+            17 => Record::SyncSurface {
+                id: get_u32(input)?,
+            },
+            18 => Record::SyncBuffer {
+                id: get_u32(input)?,
+            },
+            tag => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown record {tag}"),
+                ))
+            }
+        }))
+    }
+}
+
+fn put_u32(out: &mut impl Write, v: u32) -> io::Result<()> {
+    out.write_all(&v.to_le_bytes())
+}
+
+fn put_bytes(out: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    put_u32(out, data.len() as u32)?;
+    out.write_all(data)
+}
+
+fn put_ids(out: &mut impl Write, ids: &[u32]) -> io::Result<()> {
+    put_u32(out, ids.len() as u32)?;
+    ids.iter().try_for_each(|id| put_u32(out, *id))
+}
+
+fn get_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut v = [0; 4];
+    input.read_exact(&mut v)?;
+    Ok(u32::from_le_bytes(v))
+}
+
+fn get_bytes(input: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut data = vec![0; get_u32(input)? as usize];
+    input.read_exact(&mut data)?;
+    Ok(data)
+}
+
+fn get_ids(input: &mut impl Read) -> io::Result<Vec<u32>> {
+    (0..get_u32(input)?).map(|_| get_u32(input)).collect()
+}
+
+// Plays a trace back on a new driver, with the options it was recorded with. Everything the
+// application mapped out of a coded buffer goes to `output`, in the order it was mapped
+pub fn replay(input: File, output: File) -> Result<(), VAStatus> {
+    let mut input = BufReader::new(input);
+    let mut output = BufWriter::new(output);
+
+    let opts = read_header(&mut input).map_err(|e| {
+        error!("can't read trace: {e}");
+        VA_STATUS_ERROR_OPERATION_FAILED
+    })?;
+    let driver = Driver::new(DriverConfig::from_opts(&opts), None)?;
+
+    loop {
+        let record = match Record::read(&mut input) {
+            Ok(Some(record)) => record,
+            Ok(None) => break,
+            Err(e) => {
+                error!("can't read trace: {e}");
+                return Err(VA_STATUS_ERROR_OPERATION_FAILED);
+            }
+        };
+
+        if let Err(e) = replay_record(&driver, &record, &mut output) {
+            error!("replaying {record:?} failed: {e:#x}");
+            return Err(e);
+        }
+    }
+
+    output.flush().map_err(|_| VA_STATUS_ERROR_OPERATION_FAILED)
+}
+
+fn replay_record(
+    driver: &Driver,
+    record: &Record,
+    output: &mut impl Write,
+) -> Result<(), VAStatus> {
+    match record {
+        Record::CreateConfig {
+            profile,
+            entrypoint,
+            attribs,
+            id,
+        } => {
+            let attribs: Vec<_> = attribs
+                .iter()
+                .map(|&(type_, value)| VAConfigAttrib { type_, value })
+                .collect();
+            same_id(driver.create_config(*profile, *entrypoint, &attribs)?, *id)
+        }
+        Record::DestroyConfig { id } => driver.destroy_config(*id),
+        Record::CreateSurfaces {
+            format,
+            width,
+            height,
+            fourcc,
+            ids,
+        } => {
+            let mut attribs = Vec::new();
+            if *fourcc != 0 {
+                let mut attrib = VASurfaceAttrib::default();
+                attrib.type_ = VASurfaceAttribType_VASurfaceAttribPixelFormat;
+                attrib.flags = VA_SURFACE_ATTRIB_SETTABLE;
+                attrib.value.type_ = VAGenericValueType_VAGenericValueTypeInteger;
+                attrib.value.value.i = *fourcc as i32;
+                attribs.push(attrib);
+            }
+
+            let mut surfaces = vec![0; ids.len()];
+            driver.create_surfaces(*format, *width, *height, &mut surfaces, &attribs)?;
+            surfaces
+                .iter()
+                .zip(ids)
+                .try_for_each(|(got, id)| same_id(*got, *id))
+        }
+        Record::ImportSurface {
+            width,
+            height,
+            fourcc,
+            planes,
+            size,
+            id,
+            ..
+        } => {
+            let layout = format::layout(*fourcc).ok_or(VA_STATUS_ERROR_INVALID_IMAGE_FORMAT)?;
+            same_id(
+                driver.alloc_surface(*width, *height, layout, planes.clone(), *size as usize)?,
+                *id,
+            )
+        }
+        Record::DestroySurfaces { ids } => driver.destroy_surfaces(ids),
+        Record::CreateContext {
+            config,
+            width,
+            height,
+            flag,
+            render_targets,
+            id,
+        } => same_id(
+            driver.create_context(*config, *width, *height, *flag, render_targets)?,
+            *id,
+        ),
+        Record::DestroyContext { id } => driver.destroy_context(*id),
+        Record::CreateBuffer {
+            context,
+            type_,
+            size,
+            num_elements,
+            data,
+            id,
+        } => {
+            let mut data = data.clone();
+            if let (VABufferType_VAProcPipelineParameterBufferType, Some(data)) =
+                (*type_, &mut data)
+            {
+                clear_pipeline_pointers(data)?;
+            }
+            same_id(
+                driver.create_buffer(*context, *type_, *size, *num_elements, data.as_deref())?,
+                *id,
+            )
+        }
+        Record::DestroyBuffer { id } => driver.destroy_buffer(*id),
+        Record::MapBuffer { id } => {
+            // the frame may still have been in x264, as it was for the application
+            driver.finish_coded(*id)?;
+            let buf = driver.buffer(*id)?;
+            if let Buffer::CodedBufferSegment(bitstream, ..) = &*lock(&buf) {
+                output
+                    .write_all(bitstream)
+                    .map_err(|_| VA_STATUS_ERROR_OPERATION_FAILED)?;
+            }
+            Ok(())
+        }
+        Record::DeriveImage { surface, id } => {
+            same_id(driver.derive_image(*surface)?.image_id, *id)
+        }
+        Record::DestroyImage { id } => driver.destroy_image(*id),
+        Record::BeginPicture { context, target } => driver.begin_picture(*context, *target),
+        Record::RenderPicture { context, buffers } => driver.render_picture(*context, buffers),
+        Record::EndPicture { context } => driver.end_picture(*context),
+        Record::BufferData { id, data } => fill(&driver.buffer(*id)?, data),
+        Record::SurfaceData { id, data } => {
+            let buffer_id = lock(&driver.surface(*id)?).buffer_id;
+            fill(&driver.buffer(buffer_id)?, data)
+        }
+        Record::SyncSurface { id } => driver.sync_surface(*id),
+        Record::SyncBuffer { id } => driver.sync_buffer(*id),
+    }
+}
+
+// anything else means the replay has wandered off from what the application saw
+fn same_id(got: u32, recorded: u32) -> Result<(), VAStatus> {
+    if got != recorded {
+        error!("replay got ID {got:#x}, the trace has {recorded:#x}");
+        return Err(VA_STATUS_ERROR_OPERATION_FAILED);
+    }
+    Ok(())
+}
+
+fn fill(buf: &Mutex<Buffer>, data: &[u8]) -> Result<(), VAStatus> {
+    let mut buf = lock(buf);
+    let map = buf.map_mut()?;
+    if map.len() != data.len() {
+        return Err(VA_STATUS_ERROR_OPERATION_FAILED);
+    }
+    map.copy_from_slice(data);
+    Ok(())
+}
+
+// The pointers in a pipeline buffer were into the recording application's memory. The call
+// succeeded there, so they were all null, or a region that covered the whole surface, which null
+// means too
+fn clear_pipeline_pointers(data: &mut [u8]) -> Result<(), VAStatus> {
+    if data.len() < std::mem::size_of::<VAProcPipelineParameterBuffer>() {
+        return Err(VA_STATUS_ERROR_OPERATION_FAILED);
+    }
+
+    let pipeline = data.as_mut_ptr() as *mut VAProcPipelineParameterBuffer;
+    unsafe {
+        let mut p = pipeline.read_unaligned();
+        p.surface_region = null_mut();
+        p.output_region = null_mut();
+        p.filters = null_mut();
+        p.forward_references = null_mut();
+        p.backward_references = null_mut();
+        p.blend_state = null_mut();
+        p.additional_outputs = null_mut();
+        pipeline.write_unaligned(p);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // one of each, with every field set to something that would show up if it went missing
+    fn records() -> Vec<Record> {
+        vec![
+            Record::CreateConfig {
+                profile: VAProfile_VAProfileH264Main,
+                entrypoint: VAEntrypoint_VAEntrypointEncSlice,
+                attribs: vec![(1, 2), (3, 4)],
+                id: 5,
+            },
+            Record::DestroyConfig { id: 6 },
+            Record::CreateSurfaces {
+                format: VA_RT_FORMAT_YUV420,
+                width: 1920,
+                height: 1080,
+                fourcc: VA_FOURCC_NV12,
+                ids: vec![7, 8, 9],
+            },
+            Record::ImportSurface {
+                mem_type: VA_SURFACE_ATTRIB_MEM_TYPE_DRM_PRIME_2,
+                width: 640,
+                height: 480,
+                fourcc: VA_FOURCC_I420,
+                planes: vec![
+                    PlaneInfo {
+                        pitch: 640,
+                        offset: 0,
+                    },
+                    PlaneInfo {
+                        pitch: 320,
+                        offset: 307200,
+                    },
+                ],
+                size: 500000,
+                id: 10,
+            },
+            Record::DestroySurfaces { ids: vec![11] },
+            Record::CreateContext {
+                config: 12,
+                width: 1920,
+                height: 1080,
+                flag: VA_PROGRESSIVE as i32,
+                render_targets: vec![13, 14],
+                id: 15,
+            },
+            Record::DestroyContext { id: 16 },
+            Record::CreateBuffer {
+                context: 17,
+                type_: VABufferType_VAEncSequenceParameterBufferType,
+                size: 3,
+                num_elements: 1,
+                data: Some(vec![1, 2, 3]),
+                id: 18,
+            },
+            Record::CreateBuffer {
+                context: 19,
+                type_: VABufferType_VAEncCodedBufferType,
+                size: 1 << 20,
+                num_elements: 1,
+                data: None,
+                id: 20,
+            },
+            Record::DestroyBuffer { id: 21 },
+            Record::MapBuffer { id: 22 },
+            Record::DeriveImage {
+                surface: 23,
+                id: 24,
+            },
+            Record::DestroyImage { id: 25 },
+            Record::BeginPicture {
+                context: 26,
+                target: 27,
+            },
+            Record::RenderPicture {
+                context: 28,
+                buffers: vec![29, 30],
+            },
+            Record::EndPicture { context: 31 },
+            Record::BufferData {
+                id: 32,
+                data: vec![4, 5],
+            },
+            Record::SurfaceData {
+                id: 33,
+                data: vec![6; 100],
+            },
+            Record::SyncSurface { id: 34 },
+            Record::SyncBuffer { id: 35 },
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        let opts = vec!["preset=fast".to_string(), "threads=4".to_string()];
+        let mut trace = Vec::new();
+        write_header(&mut trace, &opts).unwrap();
+        for record in records() {
+            record.write(&mut trace).unwrap();
+        }
+
+        let mut input = &trace[..];
+        assert_eq!(read_header(&mut input).unwrap(), opts);
+        for record in records() {
+            // Record has nothing to compare with but Debug
+            let read = Record::read(&mut input).unwrap().unwrap();
+            assert_eq!(format!("{read:?}"), format!("{record:?}"));
+        }
+        assert!(Record::read(&mut input).unwrap().is_none());
+    }
+
+    #[test]
+    fn not_a_trace() {
+        assert!(read_header(&mut &b"VAX264TX\x01\0\0\0\0\0\0\0"[..]).is_err());
+
+        let mut trace = Vec::new();
+        write_header(&mut trace, &[]).unwrap();
+        trace[8..12].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(read_header(&mut &trace[..]).is_err());
+    }
+
+    #[test]
+    fn broken_records() {
+        // cut off inside a record, rather than between two
+        let mut trace = Vec::new();
+        Record::SurfaceData {
+            id: 1,
+            data: vec![0; 16],
+        }
+        .write(&mut trace)
+        .unwrap();
+        trace.pop();
+        assert!(Record::read(&mut &trace[..]).is_err());
+
+        let unknown = 1000u32.to_le_bytes();
+        assert!(Record::read(&mut &unknown[..]).is_err());
+    }
+}